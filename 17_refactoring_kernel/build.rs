@@ -4,7 +4,7 @@ fn main() {
     let obj_path = format!(
         "{}/{}",
         manifest_dir,
-        "../17_refactoring_userland/target/riscv32i-unknown-none-elf/debug/shell_bin.o"
+        "../17_refactoring_userland/target/riscv32i-unknown-none-elf/debug/shell_elf.o"
     );
 
     // Pass the object file path directly to the linker
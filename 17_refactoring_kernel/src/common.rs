@@ -1,7 +1,6 @@
 /*
 memory
 */
-pub const USER_BASE: usize = 0x1000000;
 pub const PAGE_SIZE: usize = 4096;
 pub const PAGE_TABLE_ENTRY: usize = 1024;
 /*
@@ -23,6 +22,24 @@ pub const PAGE_R: usize = 1 << 1; // 読み込み可能
 pub const PAGE_W: usize = 1 << 2; // 書き込み可能
 pub const PAGE_X: usize = 1 << 3; // 実行可能
 pub const PAGE_U: usize = 1 << 4; // ユーザーモードでアクセス可能
+pub const PAGE_COW: usize = 1 << 8; // コピーオンライト (ソフトウェア用のRSWビット)
+pub const PAGE_SHARED: usize = 1 << 9; // 共有メモリ (ソフトウェア用のRSWビット)
+pub const PAGE_FLAGS_MASK: usize = 0x3ff; // ページテーブルエントリのフラグ部分 (下位10ビット)
+
+/*
+elf
+*/
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+pub const EI_CLASS: usize = 4;
+pub const EI_DATA: usize = 5;
+pub const ELFCLASS32: u8 = 1;
+pub const ELFDATA2LSB: u8 = 1; // リトルエンディアン
+pub const ET_EXEC: u16 = 2; // 実行ファイル
+pub const EM_RISCV: u16 = 243;
+pub const PT_LOAD: u32 = 1; // メモリに読み込むセグメント
+pub const PF_X: u32 = 1 << 0; // 実行可能
+pub const PF_W: u32 = 1 << 1; // 書き込み可能
+pub const PF_R: u32 = 1 << 2; // 読み込み可能
 
 /*
 disk
@@ -54,14 +71,24 @@ pub const VIRTIO_BLK_T_IN: u32 = 0;
 pub const VIRTIO_BLK_T_OUT: u32 = 1;
 
 /*
-fs
+process
 */
-pub const FILES_MAX: usize = 2;
-
+pub const KERNEL_STACK_SIZE: usize = 8192;
 /*
-process
+スケジューラ (マルチレベルフィードバックキュー)
+- レベルが小さいほど優先度が高い
+- タイムスライスを使い切ったプロセスはレベルが1つ下がる
+- nice 値は、プロセスがいられる最も高いレベルを決める
 */
-pub const PROCS_MAX: usize = 8;
+pub const SCHED_LEVELS: usize = 8;
+pub const SCHED_BOOST_INTERVAL: usize = 100; // 全プロセスのレベルを元に戻す間隔 (タイマー割り込みの回数)
+pub const NICE_MIN: i32 = -20;
+pub const NICE_MAX: i32 = 19;
+pub const FDS_MAX: usize = 16; // プロセスごとのファイルディスクリプタの数の上限
+pub const PIPE_SIZE: usize = 4096; // パイプのバッファの大きさ
+pub const SHM_PAGES_MAX: usize = 64; // 1つの共有メモリの大きさの上限 (ページ数)
+pub const IPC_MSG_SIZE: usize = 32; // プロセス間通信のメッセージの大きさ (固定長)
+pub const FS_WRITEBACK_INTERVAL_MS: u64 = 1000; // ファイルシステムをディスクに書き戻す間隔
 
 /*
 interrupt
@@ -78,7 +105,34 @@ sstatusレジスタのSUM(permit Supervisor User Memory access)ビット
 - S-Modeのプログラム (カーネル) はU-Mode (ユーザー) のページにアクセスできない。
 */
 pub const SSTATUS_SUM: usize = 1 << 18;
+/*
+sstatusレジスタのSPPビット
+- トラップ発生前のモードを表す (0: U-Mode, 1: S-Mode)
+*/
+pub const SSTATUS_SPP: usize = 1 << 8;
 pub const SCAUSE_ECALL: usize = 8;
+pub const SCAUSE_INST_PAGE_FAULT: usize = 12;
+pub const SCAUSE_LOAD_PAGE_FAULT: usize = 13;
+pub const SCAUSE_STORE_PAGE_FAULT: usize = 15;
+/*
+scauseレジスタの最上位ビット
+- セットされている場合は割り込み、されていない場合は例外を表す
+- 残りのビットが割り込みの種類を表す
+*/
+pub const SCAUSE_INTERRUPT: usize = 1 << 31;
+pub const SCAUSE_SUPERVISOR_TIMER: usize = 5;
+/*
+sieレジスタのSTIEビット
+- S-Modeのタイマー割り込みを有効にする
+- カーネル実行中は sstatus の SIE ビットが立っていないので、割り込みはU-Mode実行中にだけ発生する
+*/
+pub const SIE_STIE: usize = 1 << 5;
+
+/*
+timer
+*/
+pub const TIMER_FREQ: u64 = 10_000_000; // QEMU virt マシンの time の周波数 (10MHz)
+pub const TIMER_INTERVAL: u64 = TIMER_FREQ / 100; // タイムスライスの長さ (10ms)
 
 /*
 syscall
@@ -88,6 +142,44 @@ pub const SYS_GETCHAR: usize = 2;
 pub const SYS_EXIT: usize = 3;
 pub const SYS_READFILE: usize = 4;
 pub const SYS_WRITEFILE: usize = 5;
+pub const SYS_FREE_PAGES: usize = 6;
+pub const SYS_SLABINFO: usize = 7;
+pub const SYS_FORK: usize = 8;
+pub const SYS_EXEC: usize = 9;
+pub const SYS_WAIT: usize = 10;
+pub const SYS_GETPID: usize = 11;
+pub const SYS_GETPPID: usize = 12;
+pub const SYS_NICE: usize = 13;
+pub const SYS_SETPRIORITY: usize = 14;
+pub const SYS_SLEEP: usize = 15;
+pub const SYS_THREAD_CREATE: usize = 16;
+pub const SYS_THREAD_EXIT: usize = 17;
+pub const SYS_THREAD_JOIN: usize = 18;
+pub const SYS_FUTEX_WAIT: usize = 19;
+pub const SYS_FUTEX_WAKE: usize = 20;
+pub const SYS_IPC_SEND: usize = 21;
+pub const SYS_IPC_RECV: usize = 22;
+pub const SYS_IPC_REPLY: usize = 23;
+pub const SYS_PIPE: usize = 24;
+pub const SYS_READ: usize = 25;
+pub const SYS_WRITE: usize = 26;
+pub const SYS_CLOSE: usize = 27;
+pub const SYS_DUP2: usize = 28;
+pub const SYS_SHM_CREATE: usize = 29;
+pub const SYS_SHM_MAP: usize = 30;
+pub const SYS_KILL: usize = 31;
+pub const SYS_SIGNAL: usize = 32;
+pub const SYS_SIGRETURN: usize = 33;
+
+/*
+signal
+*/
+pub const NSIG: usize = 32; // シグナルの数 (0 は使わない)
+pub const SIGINT: usize = 2; // コンソールで Ctrl-C が押された
+pub const SIGKILL: usize = 9; // 捕捉も無視もできない終了
+pub const SIGCHLD: usize = 17; // 子プロセスが終了した (既定では無視する)
+pub const SIG_DFL: usize = 0; // 既定の動作
+pub const SIG_IGN: usize = 1; // 無視する
 
 use core::fmt::Write;
 
@@ -159,6 +251,59 @@ pub fn getchar() -> isize {
     ret.error
 }
 
+/*
+  10.1. Function: System reset (FID #0)
+
+  ```
+    struct sbiret sbi_system_reset(uint32_t reset_type, uint32_t reset_reason)
+  ```
+
+  Reset the system based on provided reset_type and reset_reason.
+  This is a synchronous call and does not return if it succeeds.
+  -- "RISC-V Supervisor Binary Interface Specification" v2.0-rc1 より引用
+
+  reset_type = 0 (Shutdown)、reset_reason = 0 (No reason) でマシンの電源を切る
+*/
+pub fn shutdown() -> ! {
+    sbi_call(
+        0, /* reset_type = Shutdown */
+        0, /* reset_reason = No reason */
+        0, 0, 0, 0, 0,          /* FunctionID = 0 (System Reset) */
+        0x53525354, /* ExtensionID = "SRST" */
+    );
+
+    // System Reset 拡張がない場合は、Legacy の Shutdown (EID #0x08) を試す
+    sbi_call(0, 0, 0, 0, 0, 0, 0, 8);
+
+    panic!("failed to shut down");
+}
+
+/*
+  6.1. Function: Set Timer (FID #0)
+
+  ```
+    struct sbiret sbi_set_timer(uint64_t stime_value)
+  ```
+
+  Programs the clock for next event after stime_value time. stime_value is in absolute time.
+  This function must clear the pending timer interrupt bit as well.
+  -- "RISC-V Supervisor Binary Interface Specification" v2.0-rc1 より引用
+
+  Sstc 拡張がある環境では、OpenSBI が stimecmp レジスタに書き込むことで実現されます。
+*/
+pub fn set_timer(stime_value: u64) {
+    sbi_call(
+        stime_value as u32 as isize,
+        (stime_value >> 32) as u32 as isize, // RV32 では上位32ビットを a1 で渡す
+        0,
+        0,
+        0,
+        0,
+        0,          /* FunctionID = 0 (Set Timer) */
+        0x54494D45, /* ExtensionID = "TIME" */
+    );
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct SbiRet {
     pub error: isize,
@@ -0,0 +1,64 @@
+use alloc::collections::VecDeque;
+
+use crate::common::SIGINT;
+use crate::process::Process;
+use crate::signal;
+use crate::wait::WaitQueue;
+
+// コンソールから読み込んだが、まだプロセスに渡していない文字
+static mut INPUT: VecDeque<u8> = VecDeque::new();
+
+const CTRL_C: u8 = 0x03;
+
+// コンソールからの入力を待っているプロセス
+static mut INPUT_WAITERS: WaitQueue = WaitQueue::new();
+
+// SBI からコンソールに届いている文字を全て読み込み、入力を待っているプロセスを起こす
+// UART の割り込みを使っていないので、タイマー割り込みとアイドルプロセスから定期的に呼ばれる
+pub fn poll() {
+    unsafe {
+        let input = &mut *(&raw mut INPUT);
+        let len = input.len();
+        loop {
+            let c = crate::common::getchar();
+            if c < 0 {
+                break;
+            }
+
+            // Ctrl-C は入力として渡さず、シェルが実行しているジョブに SIGINT を送る
+            if c as u8 == CTRL_C {
+                crate::common::println!("^C");
+                Process::kill_job(SIGINT);
+                continue;
+            }
+            input.push_back(c as u8);
+        }
+
+        if input.len() > len {
+            (*(&raw mut INPUT_WAITERS)).wake_all();
+        }
+    }
+}
+
+// 届いている文字があれば1文字読み込む (眠らない)
+pub fn try_getchar() -> Option<u8> {
+    poll();
+    unsafe { (*(&raw mut INPUT)).pop_front() }
+}
+
+// コンソールから1文字読み込む
+// 入力がなければ、文字が届くまで実行中のプロセスを眠らせる
+// 待っている間にシグナルが届いた場合は None を返す
+pub fn getchar() -> Option<u8> {
+    loop {
+        if let Some(c) = try_getchar() {
+            return Some(c);
+        }
+
+        if signal::interrupted() {
+            return None;
+        }
+
+        unsafe { (*(&raw mut INPUT_WAITERS)).sleep() };
+    }
+}
@@ -7,15 +7,27 @@ use crate::common::{
     VIRTIO_STATUS_FEAT_OK, VIRTQ_AVAIL_F_NO_INTERRUPT, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
     VIRTQ_ENTRY_NUM,
 };
+use crate::memory::{SlabCache, SlabStats};
+use crate::wait::WaitQueue;
 
 const FIXED_SIZE_BEFORE_PADDING: usize =
     core::mem::size_of::<[VirtqDesc; VIRTQ_ENTRY_NUM]>() + core::mem::size_of::<VirtqAvail>();
 const PADDING_SIZE: usize =
     (PAGE_SIZE - (FIXED_SIZE_BEFORE_PADDING % PAGE_SIZE)) / core::mem::size_of::<u8>();
 
+// ディスクの完了や、他のプロセスのリクエストが終わるのを待っているプロセス
+static mut DISK_WAITERS: WaitQueue = WaitQueue::new();
+
+// ディスクを待っているプロセスを起こす
+// 起こされたプロセスは、リクエストが終わったかを自分で確認し直す
+pub fn wake_waiters() {
+    unsafe { (*(&raw mut DISK_WAITERS)).wake_all() };
+}
+
 pub struct Device<'a> {
     vq: &'a mut VirtioVirtq,
     req: &'a mut VirtioBlkReq,
+    in_use: bool, // リクエストを処理中か (リクエスト用の領域は1つしかないため)
 }
 
 impl<'a> Device<'a> {
@@ -58,10 +70,16 @@ impl<'a> Device<'a> {
             Device {
                 vq: &mut *vq as &mut VirtioVirtq,
                 req: &mut *req as &mut VirtioBlkReq,
+                in_use: false,
             }
         }
     }
 
+    // ディスクの容量 (バイト数) を返す
+    pub fn capacity(&self) -> usize {
+        virtio_get_blk_capacity()
+    }
+
     // virtio-blkデバイスの読み書き
     pub fn read_write_disk(&mut self, buf: &mut [u8], sector: usize, is_write: bool) {
         // 指定されたセクターがデバイスの容量内に収まっているかを確認
@@ -75,6 +93,12 @@ impl<'a> Device<'a> {
             return;
         }
 
+        // 他のプロセスのリクエストが終わるまで待つ
+        while self.in_use {
+            unsafe { (*(&raw mut DISK_WAITERS)).sleep() };
+        }
+        self.in_use = true;
+
         // virtio-blkの仕様に従って、リクエストを構築する
         let blk_req_paddr = self.req as *const VirtioBlkReq as usize;
 
@@ -129,21 +153,22 @@ impl<'a> Device<'a> {
         // デバイスに新しいリクエストがあることを通知する
         self.vq.kick(0);
 
-        // デバイス側の処理が終わるまで待つ (ビジーウェイト)
-        while self.vq.is_busy() {}
+        // デバイス側の処理が終わるまで待つ
+        // 割り込みを使っていないので、タイマー割り込みのたびに起こされて完了を確認する
+        while self.vq.is_busy() {
+            unsafe { (*(&raw mut DISK_WAITERS)).sleep() };
+        }
 
         // virtio-blk: 0でない値が返ってきたらエラー
-        if self.req.status != 0 {
+        let status = self.req.status;
+        if status != 0 {
             crate::common::println!(
                 "virtio: warn: failed to read/write sector={} status={}",
                 sector,
-                self.req.status
+                status
             );
-            return;
-        }
-
-        // 読み込み処理の場合は、バッファにデータをコピーする
-        if !is_write {
+        } else if !is_write {
+            // 読み込み処理の場合は、バッファにデータをコピーする
             unsafe {
                 core::ptr::copy_nonoverlapping(
                     self.req.data.as_ptr(),
@@ -152,6 +177,10 @@ impl<'a> Device<'a> {
                 );
             }
         }
+
+        // 次のリクエストを待っているプロセスを起こす
+        self.in_use = false;
+        wake_waiters();
     }
 }
 
@@ -172,9 +201,11 @@ struct VirtioVirtq {
 impl VirtioVirtq {
     fn new(index: usize) -> *mut Self {
         unsafe {
+            // 仮想キューは物理的に連続している必要があるので、バディアロケータから 2^order ページのブロックとして確保する
             let pages_of_vq =
                 crate::memory::align_up(core::mem::size_of::<Self>(), PAGE_SIZE) / PAGE_SIZE;
-            let vq_paddr = crate::memory::alloc_pages(pages_of_vq);
+            let vq_paddr =
+                crate::memory::alloc_pages_order(crate::memory::pages_to_order(pages_of_vq));
             let vq_ptr = vq_paddr as *mut Self;
 
             (*vq_ptr).queue_index = index as i32;
@@ -355,15 +386,30 @@ struct VirtioBlkReq {
     status: u8,
 }
 
+// virtio-blkへの処理要求のスラブキャッシュ
+// スラブは1ページに収まるので、リクエストが物理ページをまたぐことはない
+static mut BLK_REQ_CACHE: SlabCache<VirtioBlkReq> =
+    SlabCache::new("virtio_blk_req", VirtioBlkReq::empty);
+
+pub fn blk_req_cache_stats() -> SlabStats {
+    unsafe { (*(&raw const BLK_REQ_CACHE)).stats() }
+}
+
 impl VirtioBlkReq {
     fn new() -> *mut Self {
         // デバイスへの処理要求を格納する領域を確保
-        let pages_of_blk_req =
-            crate::memory::align_up(core::mem::size_of::<VirtioBlkReq>(), PAGE_SIZE) / PAGE_SIZE;
-        let blk_req_paddr = crate::memory::alloc_pages(pages_of_blk_req);
-        let blk_req_ptr = blk_req_paddr as *mut Self;
+        unsafe { (*(&raw mut BLK_REQ_CACHE)).alloc() }
+    }
 
-        blk_req_ptr
+    // スラブキャッシュのコンストラクタ
+    fn empty() -> Self {
+        VirtioBlkReq {
+            type_: 0,
+            reserved: 0,
+            sector: 0,
+            data: [0; SECTOR_SIZE],
+            status: 0,
+        }
     }
 }
 
@@ -0,0 +1,127 @@
+use crate::common::{
+    EI_CLASS, EI_DATA, ELF_MAGIC, ELFCLASS32, ELFDATA2LSB, EM_RISCV, ET_EXEC, PF_R, PF_W, PF_X,
+    PT_LOAD,
+};
+
+/*
+ELF32 実行ファイルの解析
+- ELFヘッダ: ファイルの先頭にあり、エントリポイントやプログラムヘッダの位置を持つ
+- プログラムヘッダ: ファイルのどの範囲を、どの仮想アドレスにどの権限で読み込むかを表す
+
+https://refspecs.linuxfoundation.org/elf/elf.pdf
+*/
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Elf32Header {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u32,
+    e_phoff: u32,
+    e_shoff: u32,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Elf32ProgramHeader {
+    pub p_type: u32,
+    pub p_offset: u32,
+    pub p_vaddr: u32,
+    pub p_paddr: u32,
+    pub p_filesz: u32,
+    pub p_memsz: u32,
+    pub p_flags: u32,
+    pub p_align: u32,
+}
+
+impl Elf32ProgramHeader {
+    pub fn is_load(&self) -> bool {
+        self.p_type == PT_LOAD
+    }
+
+    pub fn is_readable(&self) -> bool {
+        (self.p_flags & PF_R) != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        (self.p_flags & PF_W) != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        (self.p_flags & PF_X) != 0
+    }
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: Elf32Header,
+}
+
+impl<'a> Elf<'a> {
+    // RISC-V 32bit の実行ファイルとして正しい形式かを確認する
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        let header: Elf32Header = read_struct(data, 0)?;
+
+        if header.e_ident[..4] != ELF_MAGIC
+            || header.e_ident[EI_CLASS] != ELFCLASS32
+            || header.e_ident[EI_DATA] != ELFDATA2LSB
+            || header.e_type != ET_EXEC
+            || header.e_machine != EM_RISCV
+            || header.e_phentsize as usize != core::mem::size_of::<Elf32ProgramHeader>()
+        {
+            return None;
+        }
+
+        let elf = Elf { data, header };
+
+        // 全てのプログラムヘッダがファイル内に収まっているか確認
+        for i in 0..elf.header.e_phnum as usize {
+            let phdr = elf.program_header(i)?;
+            if phdr.is_load() {
+                let file_end = (phdr.p_offset as usize).checked_add(phdr.p_filesz as usize)?;
+                if file_end > data.len() || phdr.p_filesz > phdr.p_memsz {
+                    return None;
+                }
+            }
+        }
+
+        Some(elf)
+    }
+
+    pub fn entry(&self) -> usize {
+        self.header.e_entry as usize
+    }
+
+    fn program_header(&self, index: usize) -> Option<Elf32ProgramHeader> {
+        let offset = index
+            .checked_mul(core::mem::size_of::<Elf32ProgramHeader>())?
+            .checked_add(self.header.e_phoff as usize)?;
+        read_struct(self.data, offset)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = Elf32ProgramHeader> + '_ {
+        (0..self.header.e_phnum as usize).filter_map(|i| self.program_header(i))
+    }
+}
+
+// バイト列の offset から構造体を読み出す (アラインメントされていなくてもよい)
+fn read_struct<T: Copy>(data: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(core::mem::size_of::<T>())?;
+    if end > data.len() {
+        return None;
+    }
+
+    unsafe {
+        Some(core::ptr::read_unaligned(
+            data.as_ptr().add(offset) as *const T
+        ))
+    }
+}
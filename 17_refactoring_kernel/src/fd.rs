@@ -0,0 +1,87 @@
+use alloc::vec::Vec;
+
+use crate::common::FDS_MAX;
+use crate::pipe::{PipeReader, PipeWriter};
+
+// ファイルディスクリプタが指すもの
+#[derive(Clone)]
+pub enum Handle {
+    Console,
+    PipeReader(PipeReader),
+    PipeWriter(PipeWriter),
+}
+
+// プロセスごとのファイルディスクリプタの表
+// fork では表ごと複製し、exec では引き継ぐ
+#[derive(Clone)]
+pub struct FdTable {
+    fds: Vec<Option<Handle>>,
+}
+
+impl FdTable {
+    pub const fn new() -> Self {
+        FdTable { fds: Vec::new() }
+    }
+
+    // 標準入力 (0)、標準出力 (1)、標準エラー出力 (2) をコンソールにつないだ表を作る
+    pub fn with_console() -> Self {
+        FdTable {
+            fds: alloc::vec![
+                Some(Handle::Console),
+                Some(Handle::Console),
+                Some(Handle::Console),
+            ],
+        }
+    }
+
+    pub fn get(&self, fd: i32) -> Option<&Handle> {
+        self.fds.get(usize::try_from(fd).ok()?)?.as_ref()
+    }
+
+    // 空いている最も小さい番号に handle を割り当てる
+    // 空きがなければ None を返す
+    pub fn alloc(&mut self, handle: Handle) -> Option<i32> {
+        let fd = match self.fds.iter().position(|h| h.is_none()) {
+            Some(fd) => fd,
+            None if self.fds.len() < FDS_MAX => {
+                self.fds.push(None);
+                self.fds.len() - 1
+            }
+            None => return None,
+        };
+
+        self.fds[fd] = Some(handle);
+        Some(fd as i32)
+    }
+
+    pub fn close(&mut self, fd: i32) -> bool {
+        match usize::try_from(fd).ok().and_then(|fd| self.fds.get_mut(fd)) {
+            Some(handle) => handle.take().is_some(),
+            None => false,
+        }
+    }
+
+    // old_fd と同じものを new_fd にも割り当てる (new_fd が使われていれば先に閉じる)
+    pub fn dup2(&mut self, old_fd: i32, new_fd: i32) -> bool {
+        let handle = match self.get(old_fd) {
+            Some(handle) => handle.clone(),
+            None => return false,
+        };
+
+        let new_fd = match usize::try_from(new_fd) {
+            Ok(new_fd) if new_fd < FDS_MAX => new_fd,
+            _ => return false,
+        };
+        if self.fds.len() <= new_fd {
+            self.fds.resize(new_fd + 1, None);
+        }
+
+        self.fds[new_fd] = Some(handle);
+        true
+    }
+
+    // 全てのファイルディスクリプタを閉じる
+    pub fn clear(&mut self) {
+        self.fds.clear();
+    }
+}
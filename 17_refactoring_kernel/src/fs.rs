@@ -1,8 +1,12 @@
-use crate::common::{FILES_MAX, SECTOR_SIZE};
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+
+use crate::common::SECTOR_SIZE;
 use crate::disk::Device;
+use crate::memory::{SlabCache, SlabStats};
 
-const DISK_MAX_SIZE: usize =
-    crate::memory::align_up(core::mem::size_of::<File>() * FILES_MAX, SECTOR_SIZE) as usize;
+// tarアーカイブの終端を示す、ゼロで埋められた2つのブロック
+const TAR_END_OF_ARCHIVE_SIZE: usize = SECTOR_SIZE * 2;
 
 // tarヘッダ構造体
 #[repr(C, packed)]
@@ -77,7 +81,7 @@ impl TarHeader {
         int2oct(size, &mut self.size);
     }
 
-    fn get_checksum(&self, disk: &[u8; DISK_MAX_SIZE], offset: usize) -> usize {
+    fn get_checksum(&self, disk: &[u8], offset: usize) -> usize {
         // チェックサムを計算
         let mut checksum = b' ' as usize * self.checksum.len();
         for i in 0..core::mem::size_of::<TarHeader>() {
@@ -132,37 +136,49 @@ impl TarHeader {
         unsafe {
             let data_ptr =
                 (self as *mut TarHeader as *mut u8).add(core::mem::size_of::<TarHeader>());
-            let data_slice = core::slice::from_raw_parts_mut(data_ptr, file.size);
-            data_slice.copy_from_slice(&file.data[0..file.size]);
+            let data_slice = core::slice::from_raw_parts_mut(data_ptr, file.data.len());
+            data_slice.copy_from_slice(&file.data);
         }
     }
 }
 
+// ファイルエントリのスラブキャッシュ
+static mut FILE_CACHE: SlabCache<File> = SlabCache::new("file", File::new);
+
+pub fn file_cache_stats() -> SlabStats {
+    unsafe { (*(&raw const FILE_CACHE)).stats() }
+}
+
 pub struct FileSystem<'a> {
-    files: [File; FILES_MAX],
-    disk: [u8; DISK_MAX_SIZE],
+    files: Vec<*mut File>,
+    disk: Vec<u8>,
     device: Device<'a>,
+    dirty: bool,    // ディスクに書き戻していない変更があるか
+    flushing: bool, // ディスクに書き戻している途中か (書き込みの完了を待って眠っている間も true)
 }
 
 impl<'a> FileSystem<'a> {
     pub fn new(device: Device<'a>) -> Self {
+        let disk_size = device.capacity();
         let mut fs = FileSystem {
-            files: core::array::from_fn(|_i| File::new()),
-            disk: [0; DISK_MAX_SIZE],
+            files: Vec::new(),
+            disk: alloc::vec![0; disk_size],
             device: device,
+            dirty: false,
+            flushing: false,
         };
 
         // ディスクからデータを読み込む
-        for sector in 0..(DISK_MAX_SIZE / SECTOR_SIZE) {
+        for sector in 0..(disk_size / SECTOR_SIZE) {
             let offset = sector * SECTOR_SIZE;
             fs.device
                 .read_write_disk(&mut fs.disk[offset..], sector, false);
         }
 
-        crate::common::println!("read {} bytes from disk", DISK_MAX_SIZE);
+        crate::common::println!("read {} bytes from disk", disk_size);
 
         let mut offset = 0;
-        for i in 0..fs.files.len() {
+        while offset + core::mem::size_of::<TarHeader>() <= fs.disk.len() {
             unsafe {
                 // TARヘッダーへの参照を取得
                 let header = &mut (*(&mut fs.disk[offset] as *mut u8 as *mut TarHeader));
@@ -178,31 +194,66 @@ impl<'a> FileSystem<'a> {
                     panic!("invalid tar header: magic={}", magic_str);
                 }
 
+                // ファイルのデータがディスクに収まっているかチェック
+                let size = header.get_size();
+                let data_offset = offset + core::mem::size_of::<TarHeader>();
+                if size > fs.disk.len() - data_offset {
+                    panic!(
+                        "invalid tar header: {} has size={}, but only {} bytes remain",
+                        header.get_name(),
+                        size,
+                        fs.disk.len() - data_offset
+                    );
+                }
+
                 // ファイル構造体を設定
-                let file = &mut fs.files[i];
+                let file = &mut *(*(&raw mut FILE_CACHE)).alloc();
                 file.setup(&header);
 
                 // ファイル情報を表示
-                crate::common::println!("file: {}, size={}", file.get_name(), file.size);
+                crate::common::println!("file: {}, size={}", file.get_name(), file.data.len());
 
                 offset += crate::memory::align_up(
-                    core::mem::size_of::<TarHeader>() + file.size,
+                    core::mem::size_of::<TarHeader>() + file.data.len(),
                     SECTOR_SIZE,
                 );
+                fs.files.push(file as *mut File);
             }
         }
 
         fs
     }
 
-    pub fn flush(&mut self) {
+    fn flush(&mut self) {
+        // 全てのファイルがディスクに収まるか確認
+        let mut disk_size = TAR_END_OF_ARCHIVE_SIZE;
+        for file in self
+            .files
+            .iter()
+            .map(|&f| unsafe { &*f })
+            .filter(|f| f.in_use)
+        {
+            disk_size += crate::memory::align_up(
+                core::mem::size_of::<TarHeader>() + file.data.len(),
+                SECTOR_SIZE,
+            );
+        }
+        if disk_size > self.disk.len() {
+            crate::common::println!(
+                "fs: too large to flush: {} bytes, but capacity is {} bytes",
+                disk_size,
+                self.disk.len()
+            );
+            return;
+        }
+
         unsafe {
             // files変数の各ファイルの内容をdisk変数に書き込むために、0で初期化
             self.init_disk();
 
             let mut offset = 0;
             for i in 0..self.files.len() {
-                let file = &self.files[i];
+                let file = &*self.files[i];
                 if !file.in_use {
                     continue;
                 }
@@ -224,38 +275,61 @@ impl<'a> FileSystem<'a> {
                 header.set_version("00");
 
                 header.type_flag = b'0';
-                header.set_size(file.size);
+                header.set_size(file.data.len());
                 header.set_checksum(header.get_checksum(&self.disk, offset));
 
                 // ファイルデータをコピー
                 header.set_data(file);
 
                 offset += crate::memory::align_up(
-                    core::mem::size_of::<TarHeader>() + file.size,
+                    core::mem::size_of::<TarHeader>() + file.data.len(),
                     SECTOR_SIZE,
                 );
             }
 
-            // disk変数の内容をディスクに書き込む
-            for sector in 0..(DISK_MAX_SIZE / SECTOR_SIZE) {
+            // disk変数の内容をディスクに書き込む (使用している領域と終端ブロックのみ)
+            for sector in 0..(disk_size / SECTOR_SIZE) {
                 let off = sector * SECTOR_SIZE;
                 self.device
                     .read_write_disk(&mut self.disk[off..], sector, true);
             }
 
-            crate::common::println!("wrote {} bytes to disk", DISK_MAX_SIZE);
+            crate::common::println!("wrote {} bytes to disk", disk_size);
+        }
+    }
+
+    // ファイルの内容を変更したことを記録する
+    // ディスクへの書き戻しは、書き戻し用のカーネルスレッドが sync() でまとめて行う
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    // 変更があればディスクに書き戻す
+    // 他のプロセスが書き戻している途中であれば何もしない (書き戻し中の変更は dirty に残る)
+    pub fn sync(&mut self) {
+        if self.dirty && !self.flushing {
+            self.dirty = false;
+            self.flushing = true;
+            self.flush();
+            self.flushing = false;
         }
     }
 
+    // 書き戻している途中か
+    pub fn is_flushing(&self) -> bool {
+        self.flushing
+    }
+
     fn init_disk(&mut self) {
-        self.disk = [0; DISK_MAX_SIZE];
+        self.disk.fill(0);
     }
 
     pub fn lookup(&mut self, filename: &[u8]) -> Option<&mut File> {
-        for i in 0..FILES_MAX {
-            let file = &self.files[i];
-            if file.get_name() == core::str::from_utf8(filename).unwrap() {
-                return Some(&mut self.files[i]);
+        for i in 0..self.files.len() {
+            let file = unsafe { &mut *self.files[i] };
+            // ユーザーから渡された名前は UTF-8 とは限らないので、バイト列のまま比べる
+            if file.get_name().as_bytes() == filename {
+                return Some(file);
             }
         }
 
@@ -265,10 +339,9 @@ impl<'a> FileSystem<'a> {
 
 #[derive(Debug)]
 pub struct File {
-    in_use: bool,         // このファイルエントリが使われているか
-    pub name: [u8; 100],  // ファイル名
-    pub data: [u8; 1024], // ファイルの内容
-    pub size: usize,      // ファイルサイズ
+    in_use: bool,        // このファイルエントリが使われているか
+    pub name: [u8; 100], // ファイル名
+    pub data: Rc<[u8]>,  // ファイルの内容 (長さがファイルサイズ、exec したプロセスとも共有する)
 }
 
 impl File {
@@ -276,8 +349,7 @@ impl File {
         File {
             in_use: false,
             name: [0; 100],
-            data: [0; 1024],
-            size: 0,
+            data: Rc::from([]),
         }
     }
 
@@ -291,7 +363,6 @@ impl File {
         self.name.copy_from_slice(&header.name);
 
         let file_size = oct2int(&header.size, header.size.len());
-        self.size = file_size;
 
         unsafe {
             // ファイルのデータフィールドにコピー
@@ -299,7 +370,7 @@ impl File {
             let data_ptr =
                 (header as *const TarHeader as *const u8).add(core::mem::size_of::<TarHeader>());
             let data_slice = core::slice::from_raw_parts(data_ptr, file_size);
-            self.data[..file_size].copy_from_slice(&data_slice[..file_size]);
+            self.data = Rc::from(data_slice);
         }
     }
 }
@@ -0,0 +1,78 @@
+use alloc::collections::BTreeMap;
+
+use crate::memory::Paddr;
+use crate::process::PROCESS_TABLE;
+use crate::wait::WaitQueue;
+
+// futex ごとの待ち行列 (キーはユーザーのアドレスに対応する物理アドレス)
+// 物理アドレスをキーにすることで、スレッドや共有メモリのように
+// 別の仮想アドレスから同じメモリを見ているプロセス同士でも同じ futex になる
+static mut FUTEXES: BTreeMap<Paddr, WaitQueue> = BTreeMap::new();
+
+// ユーザーの仮想アドレスに対応する物理アドレスを求める
+// 書き込み時にコピーされるページの場合はここでコピーしておき、待っている間に物理アドレスが変わらないようにする
+fn futex_key(vaddr: usize) -> Option<Paddr> {
+    if vaddr % core::mem::size_of::<u32>() != 0 {
+        return None;
+    }
+
+    unsafe {
+        let current = &mut *PROCESS_TABLE.current;
+        let mut vm = current.vm.borrow_mut();
+        if !vm.prepare(vaddr, core::mem::size_of::<u32>(), true) {
+            return None;
+        }
+
+        vm.page_table.translate(vaddr)
+    }
+}
+
+// vaddr の値が expected と等しければ、futex_wake() で起こされるまで眠る
+// 値を確認してから眠るまでの間に他のプロセスに切り替わることはないので、起こされ損なうことはない
+// 眠った場合は 0、値が異なっていた場合やアドレスが不正な場合は -1 を返す
+pub fn futex_wait(vaddr: usize, expected: u32) -> i32 {
+    let key = match futex_key(vaddr) {
+        Some(key) => key,
+        None => return -1,
+    };
+
+    unsafe {
+        if core::ptr::read_volatile(vaddr as *const u32) != expected {
+            return -1;
+        }
+
+        let futexes = &mut *(&raw mut FUTEXES);
+        futexes.entry(key).or_insert_with(WaitQueue::new).sleep();
+    }
+
+    0
+}
+
+// vaddr の futex で待っているプロセスを最大 n 個起こし、起こした数を返す
+// アドレスが不正な場合は -1 を返す
+pub fn futex_wake(vaddr: usize, n: usize) -> i32 {
+    let key = match futex_key(vaddr) {
+        Some(key) => key,
+        None => return -1,
+    };
+
+    unsafe {
+        let futexes = &mut *(&raw mut FUTEXES);
+        let queue = match futexes.get_mut(&key) {
+            Some(queue) => queue,
+            None => return 0,
+        };
+
+        let mut woken = 0;
+        while woken < n && queue.wake_one() {
+            woken += 1;
+        }
+
+        // 待っているプロセスがいなくなった futex は取り除く
+        if queue.is_empty() {
+            futexes.remove(&key);
+        }
+
+        woken as i32
+    }
+}
@@ -0,0 +1,173 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use crate::common::IPC_MSG_SIZE;
+use crate::process::{PROCESS_TABLE, Process, ProcessState};
+use crate::signal;
+use crate::wait::WaitQueue;
+
+/*
+プロセス間のメッセージパッシング (同期 IPC)
+1. クライアントは ipc_send() でサーバーにメッセージを送り、返信が届くまで眠る
+2. サーバーは ipc_recv() でメッセージを1つ受け取る (届いていなければ眠る)
+3. サーバーは ipc_reply() で返信し、クライアントを起こす
+
+メッセージは送信側のプロセスのカーネル領域 (Endpoint::msg) にコピーしてから受信側に渡すので、
+カーネルが実行中のプロセス以外のアドレス空間にアクセスすることはない
+*/
+#[derive(Clone, Copy, PartialEq)]
+enum IpcState {
+    Idle,
+    Sending(i32),       // サーバー pid にメッセージを送り、受け取られるのを待っている
+    AwaitingReply(i32), // サーバー pid にメッセージを受け取られ、返信を待っている
+    Replied,            // 返信が msg に届いた
+}
+
+// 各プロセスが持つ IPC の状態
+pub struct Endpoint {
+    state: IpcState,
+    msg: [u8; IPC_MSG_SIZE],  // 送信するメッセージ、または受け取った返信
+    senders: VecDeque<i32>,   // このプロセス宛てに送られ、まだ受け取っていないクライアント
+    clients: Vec<i32>,        // 受け取ったが、まだ返信していないクライアント
+    receivers: WaitQueue,     // メッセージが届くのを待っている (自分自身)
+    reply_waiters: WaitQueue, // 返信を待っている (自分自身)
+}
+
+impl Endpoint {
+    pub const fn new() -> Self {
+        Endpoint {
+            state: IpcState::Idle,
+            msg: [0; IPC_MSG_SIZE],
+            senders: VecDeque::new(),
+            clients: Vec::new(),
+            receivers: WaitQueue::new(),
+            reply_waiters: WaitQueue::new(),
+        }
+    }
+}
+
+// メッセージを受け取れる (終了していないユーザー) プロセスを探す
+// アイドルプロセスやカーネルスレッドは ipc_recv() を呼ばないので、送り先にできない
+fn lookup_alive(pid: i32) -> Option<&'static mut Process> {
+    let proc = unsafe { &mut *PROCESS_TABLE.lookup(pid)? };
+    if !proc.is_user_process() {
+        return None;
+    }
+
+    match proc.state {
+        ProcessState::Runnable | ProcessState::Sleeping | ProcessState::Blocked => Some(proc),
+        _ => None,
+    }
+}
+
+// プロセス dst に msg を送り、返信が届くまで待つ
+// 成功した場合は返信を reply にコピーして 0 を、dst が存在しないか返信前に終了した場合は -1 を返す
+// 返信を待っている間にシグナルが届いた場合も、待つのをやめて -1 を返す
+pub fn ipc_send(dst: i32, msg: &[u8; IPC_MSG_SIZE], reply: &mut [u8; IPC_MSG_SIZE]) -> i32 {
+    let current = unsafe { &mut *PROCESS_TABLE.current };
+    if dst == current.pid {
+        return -1;
+    }
+
+    let server = match lookup_alive(dst) {
+        Some(server) => server,
+        None => return -1,
+    };
+
+    current.ipc.msg.copy_from_slice(msg);
+    current.ipc.state = IpcState::Sending(dst);
+    server.ipc.senders.push_back(current.pid);
+    server.ipc.receivers.wake_all();
+
+    loop {
+        if current.ipc.state == IpcState::Replied {
+            reply.copy_from_slice(&current.ipc.msg);
+            current.ipc.state = IpcState::Idle;
+            return 0;
+        }
+
+        // 返信する前にサーバーが終了した
+        if lookup_alive(dst).is_none() {
+            current.ipc.state = IpcState::Idle;
+            return -1;
+        }
+
+        // シグナルが届いていれば、まだ受け取られていないメッセージを取り下げて待つのをやめる
+        // 受け取られた後であれば、Idle に戻したことで ipc_reply() の返信は -1 になる
+        if signal::interrupted() {
+            if let Some(server) = lookup_alive(dst) {
+                server.ipc.senders.retain(|&pid| pid != current.pid);
+            }
+            current.ipc.state = IpcState::Idle;
+            return -1;
+        }
+
+        current.ipc.reply_waiters.sleep();
+    }
+}
+
+// 実行中のプロセス宛てのメッセージを1つ受け取り、msg にコピーする
+// メッセージが届くまで眠り、送信したプロセスのIDを返す (シグナルが届いた場合は -1 を返す)
+pub fn ipc_recv(msg: &mut [u8; IPC_MSG_SIZE]) -> i32 {
+    let current = unsafe { &mut *PROCESS_TABLE.current };
+
+    loop {
+        while let Some(pid) = current.ipc.senders.pop_front() {
+            // 送信した後に終了したクライアントは飛ばす
+            let client = match lookup_alive(pid) {
+                Some(client) => client,
+                None => continue,
+            };
+            if client.ipc.state != IpcState::Sending(current.pid) {
+                continue;
+            }
+
+            msg.copy_from_slice(&client.ipc.msg);
+            client.ipc.state = IpcState::AwaitingReply(current.pid);
+            current.ipc.clients.push(pid);
+            return pid;
+        }
+
+        if signal::interrupted() {
+            return -1;
+        }
+
+        current.ipc.receivers.sleep();
+    }
+}
+
+// メッセージを受け取ったクライアント pid に reply を返信し、クライアントを起こす
+// pid が返信を待っているクライアントでない場合は -1 を返す
+pub fn ipc_reply(pid: i32, reply: &[u8; IPC_MSG_SIZE]) -> i32 {
+    let current = unsafe { &mut *PROCESS_TABLE.current };
+
+    let index = match current.ipc.clients.iter().position(|&c| c == pid) {
+        Some(index) => index,
+        None => return -1,
+    };
+    current.ipc.clients.swap_remove(index);
+
+    let client = match lookup_alive(pid) {
+        Some(client) => client,
+        None => return -1,
+    };
+    if client.ipc.state != IpcState::AwaitingReply(current.pid) {
+        return -1;
+    }
+
+    client.ipc.msg.copy_from_slice(reply);
+    client.ipc.state = IpcState::Replied;
+    client.ipc.reply_waiters.wake_all();
+
+    0
+}
+
+// 終了するプロセス宛てにメッセージを送っていたクライアントを起こす
+// 起こされたクライアントは、サーバーが終了したことに気づいて ipc_send() から -1 で戻る
+pub fn ipc_abort(endpoint: &mut Endpoint) {
+    for pid in endpoint.senders.drain(..).chain(endpoint.clients.drain(..)) {
+        if let Some(proc) = lookup_alive(pid) {
+            proc.ipc.reply_waiters.wake_all();
+        }
+    }
+}
@@ -2,18 +2,43 @@
 #![no_main]
 #![feature(fn_align)]
 
+extern crate alloc;
+
 mod common;
+mod console;
 mod disk;
+mod elf;
+mod fd;
 mod fs;
+mod futex;
+mod ipc;
 mod memory;
+mod pipe;
 mod process;
+mod shm;
+mod signal;
+mod timer;
+mod vm;
+mod wait;
+
+use alloc::boxed::Box;
+use alloc::rc::Rc;
 
 use crate::common::{
-    SCAUSE_ECALL, SYS_EXIT, SYS_GETCHAR, SYS_PUTCHAR, SYS_READFILE, SYS_WRITEFILE,
+    FS_WRITEBACK_INTERVAL_MS, IPC_MSG_SIZE, PAGE_SIZE, SCAUSE_ECALL, SCAUSE_INST_PAGE_FAULT,
+    SCAUSE_INTERRUPT, SCAUSE_LOAD_PAGE_FAULT, SCAUSE_STORE_PAGE_FAULT, SCAUSE_SUPERVISOR_TIMER,
+    SSTATUS_SPP, SYS_CLOSE, SYS_DUP2, SYS_EXEC, SYS_EXIT, SYS_FORK, SYS_FREE_PAGES, SYS_FUTEX_WAIT,
+    SYS_FUTEX_WAKE, SYS_GETCHAR, SYS_GETPID, SYS_GETPPID, SYS_IPC_RECV, SYS_IPC_REPLY,
+    SYS_IPC_SEND, SYS_KILL, SYS_NICE, SYS_PIPE, SYS_PUTCHAR, SYS_READ, SYS_READFILE,
+    SYS_SETPRIORITY, SYS_SHM_CREATE, SYS_SHM_MAP, SYS_SIGNAL, SYS_SIGRETURN, SYS_SLABINFO,
+    SYS_SLEEP, SYS_THREAD_CREATE, SYS_THREAD_EXIT, SYS_THREAD_JOIN, SYS_WAIT, SYS_WRITE,
+    SYS_WRITEFILE,
 };
 use crate::disk::Device;
+use crate::fd::Handle;
 use crate::fs::FileSystem;
-use crate::process::{PROCESS_TABLE, Process, ProcessState};
+use crate::process::{PROCESS_TABLE, Process, spawn_kernel_thread};
+use crate::vm::{Access, Image};
 
 unsafe extern "C" {
     static __bss: u8;
@@ -22,11 +47,11 @@ unsafe extern "C" {
 
 }
 
-// from shell.bin.o application
+// from shell_elf.o application
 unsafe extern "C" {
-    static _binary_target_riscv32i_unknown_none_elf_debug_shell_bin_start: u8;
-    static _binary_target_riscv32i_unknown_none_elf_debug_shell_bin_end: u8;
-    static _binary_target_riscv32i_unknown_none_elf_debug_shell_bin_size: u8;
+    static _binary_target_riscv32i_unknown_none_elf_debug_shell_elf_start: u8;
+    static _binary_target_riscv32i_unknown_none_elf_debug_shell_elf_end: u8;
+    static _binary_target_riscv32i_unknown_none_elf_debug_shell_elf_size: u8;
 }
 
 macro_rules! read_csr {
@@ -83,62 +108,98 @@ fn kernel_main() -> ! {
 
     write_csr!("stvec", kernel_entry);
 
+    timer::init();
+
     let device = Device::new();
 
     unsafe {
-        let mut filesystem = FileSystem::new(device);
-        FILE_SYSTEM = &mut filesystem as *mut FileSystem;
+        // カーネルが動いている間ずっと使うので、ヒープに置いて解放しない
+        FILE_SYSTEM = Box::into_raw(Box::new(FileSystem::new(device)));
 
-        PROCESS_TABLE.idol = Process::new(core::ptr::null(), 0);
-        (*PROCESS_TABLE.idol).set_pid(0);
+        // 最初に作るアイドルプロセスにはプロセスID 0 が割り当てられる
+        PROCESS_TABLE.idol = Process::new(Image::Empty);
         PROCESS_TABLE.current = PROCESS_TABLE.idol;
 
-        let binary_shell_bin_start =
-            &_binary_target_riscv32i_unknown_none_elf_debug_shell_bin_start as *const u8;
-        let binary_shell_bin_size =
-            &_binary_target_riscv32i_unknown_none_elf_debug_shell_bin_size as *const u8 as usize;
-        Process::new(binary_shell_bin_start, binary_shell_bin_size);
+        spawn_kernel_thread(fs_writeback, 0);
+
+        let binary_shell_elf_start =
+            &_binary_target_riscv32i_unknown_none_elf_debug_shell_elf_start as *const u8;
+        let binary_shell_elf_size =
+            &_binary_target_riscv32i_unknown_none_elf_debug_shell_elf_size as *const u8 as usize;
+        PROCESS_TABLE.init = Process::new(Image::Static(core::slice::from_raw_parts(
+            binary_shell_elf_start,
+            binary_shell_elf_size,
+        )));
+    }
+
+    // ここからはアイドルプロセスとして動く
+    // 実行可能なプロセスがない間は、wfi で CPU を止めて割り込みが保留されるまで待ち、
+    // 期限を過ぎたタイマーやコンソールの入力を処理してから、起きたプロセスに切り替える
+    // (sie でタイマー割り込みを有効にしてあれば、sstatus の SIE ビットが立っていなくても wfi から戻ってくる。
+    //  カーネル実行中にトラップしないので、アイドルプロセスの処理が割り込まれることはない)
+    loop {
+        Process::yield_proc();
+
+        // 全てのプロセスが終了したら、マシンを停止する
+        // 書き戻し用のカーネルスレッドがディスクに書き込んでいる途中であれば、終わるまで待ってから最後の変更を書き戻す
+        // (途中で止めるとディスクの内容が壊れ、途中で書き戻しを始めると書き込み中のバッファを上書きしてしまう)
+        if !Process::has_user_processes() && unsafe { !(*FILE_SYSTEM).is_flushing() } {
+            unsafe { (*FILE_SYSTEM).sync() };
+            common::println!("no processes left, shutting down");
+            common::shutdown();
+        }
+
+        unsafe { core::arch::asm!("wfi") };
+        timer::handle_interrupt();
+        poll_devices();
     }
+}
 
-    Process::yield_proc();
-    panic!("switched to idle process");
+// ファイルシステムの変更を定期的にディスクに書き戻すカーネルスレッド
+fn fs_writeback(_arg: usize) {
+    loop {
+        Process::sleep_current(FS_WRITEBACK_INTERVAL_MS);
+        unsafe { (*FILE_SYSTEM).sync() };
+    }
 }
 
 // https://ryochack.hatenablog.com/entry/2018/03/23/184943
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Default)]
 #[repr(C, packed)]
-struct TrapFrame {
-    ra: i32,
-    gp: i32,
-    tp: i32,
-    t0: i32,
-    t1: i32,
-    t2: i32,
-    t3: i32,
-    t4: i32,
-    t5: i32,
-    t6: i32,
-    a0: i32,
-    a1: i32,
-    a2: i32,
-    a3: i32,
-    a4: i32,
-    a5: i32,
-    a6: i32,
-    a7: i32,
-    s0: i32,
-    s1: i32,
-    s2: i32,
-    s3: i32,
-    s4: i32,
-    s5: i32,
-    s6: i32,
-    s7: i32,
-    s8: i32,
-    s9: i32,
-    s10: i32,
-    s11: i32,
-    sp: i32,
+pub struct TrapFrame {
+    pub ra: i32,
+    pub gp: i32,
+    pub tp: i32,
+    pub t0: i32,
+    pub t1: i32,
+    pub t2: i32,
+    pub t3: i32,
+    pub t4: i32,
+    pub t5: i32,
+    pub t6: i32,
+    pub a0: i32,
+    pub a1: i32,
+    pub a2: i32,
+    pub a3: i32,
+    pub a4: i32,
+    pub a5: i32,
+    pub a6: i32,
+    pub a7: i32,
+    pub s0: i32,
+    pub s1: i32,
+    pub s2: i32,
+    pub s3: i32,
+    pub s4: i32,
+    pub s5: i32,
+    pub s6: i32,
+    pub s7: i32,
+    pub s8: i32,
+    pub s9: i32,
+    pub s10: i32,
+    pub s11: i32,
+    pub sp: i32,
+    pub sepc: u32,    // トラップ発生時のpc (sret で戻る先)
+    pub sstatus: u32, // トラップ発生時の sstatus (sret で戻るモードなど)
 }
 
 #[repr(align(4))]
@@ -154,7 +215,7 @@ fn kernel_entry() {
             // tmp = sp; sp = sscratch; sscratch = tmp;
             "csrrw sp, sscratch, sp",
             // 全ての汎用レジスタ（ra, gp, tp, t0〜t6, a0〜a7, s0〜s11）をスタックに保存
-            "addi sp, sp, -4 * 31",
+            "addi sp, sp, -4 * 33",
             "sw ra,  4 * 0(sp)",
             "sw gp,  4 * 1(sp)",
             "sw tp,  4 * 2(sp)",
@@ -188,6 +249,12 @@ fn kernel_entry() {
             // 例外発生時のspを取り出して保存
             "csrr a0, sscratch",
             "sw a0, 4 * 30(sp)",
+            // 戻り先のpcと sstatus も保存する
+            // トラップ処理中に他のプロセスへ切り替わっても、自分の戻り先を失わないようにするため
+            "csrr a0, sepc",
+            "sw a0, 4 * 31(sp)",
+            "csrr a0, sstatus",
+            "sw a0, 4 * 32(sp)",
             // 「例外発生時のスタックポインタを信頼しない」ために、カーネルスタックを設定し直す
             // そもそも、なぜ信頼すべきではないのか考えてみましょう。
             // 例外ハンドラでは、次の3つのパターンを考慮する必要があります。
@@ -198,12 +265,30 @@ fn kernel_entry() {
             // 3. ユーザーモードで例外が発生した
             //   - このとき、spは「ユーザー (アプリケーション) のスタック領域」を指しています。
             //   - spをそのまま利用する (信頼する) 実装の場合では、不正な値をセットして例外を発生させると、カーネルをクラッシュさせる脆弱性に繋がります
-            "addi a0, sp, 4 * 31",
+            "addi a0, sp, 4 * 33",
             "csrw sscratch, a0",
             // 新しいスタックポインタを引数に設定して、
             // トラップハンドラ呼び出し
             "mv a0, sp",
             "call {handle_trap}",
+            "j {trap_return}",
+            handle_trap = sym handle_trap,
+            trap_return = sym trap_return,
+            options(noreturn)
+        );
+    }
+}
+
+// スタックに積まれた TrapFrame から全てのレジスタを復元して、トラップ発生元に戻る
+// fork で作られたプロセスは、最初のコンテキストスイッチでここに戻ってくる
+pub fn trap_return() {
+    unsafe {
+        core::arch::asm!(
+            // 戻り先のpcと sstatus を復元
+            "lw a0,  4 * 31(sp)",
+            "csrw sepc, a0",
+            "lw a0,  4 * 32(sp)",
+            "csrw sstatus, a0",
             // 保存した全てのレジスタをスタックから復元
             "lw ra,  4 * 0(sp)",
             "lw gp,  4 * 1(sp)",
@@ -240,7 +325,6 @@ fn kernel_entry() {
             // sret 命令を実行して、スーパーバイザーモード（S-mode）から戻ります
             // これにより、トラップが発生した場所に制御が戻ります
             "sret",
-            handle_trap = sym handle_trap,
             options(noreturn)
         );
     }
@@ -250,18 +334,34 @@ fn kernel_entry() {
 unsafe fn handle_trap(f: *mut TrapFrame) {
     let scause = read_csr!("scause");
     let stval = read_csr!("stval");
-    let mut user_pc = read_csr!("sepc");
 
-    if scause as usize == SCAUSE_ECALL {
-        unsafe {
-            if f.is_null() {
-                panic!("Null pointer dereference");
-            }
+    if f.is_null() {
+        panic!("Null pointer dereference");
+    }
 
-            handle_syscall(&mut *f);
-        }
+    let f = unsafe { &mut *f };
+    let user_pc = f.sepc;
 
-        user_pc += 4;
+    if (scause as usize & SCAUSE_INTERRUPT) != 0 {
+        match scause as usize & !SCAUSE_INTERRUPT {
+            SCAUSE_SUPERVISOR_TIMER => handle_timer_interrupt(),
+            _ => panic!(
+                "unexpected interrupt scause={:x}, sepc={:x}",
+                scause, user_pc
+            ),
+        }
+    } else if scause as usize == SCAUSE_ECALL {
+        // ecall 命令の次から再開する
+        // fork した子プロセスも同じ位置から再開できるよう、システムコールを処理する前に進めておく
+        f.sepc = user_pc + 4;
+        handle_syscall(f);
+    } else if (scause as usize == SCAUSE_INST_PAGE_FAULT
+        || scause as usize == SCAUSE_LOAD_PAGE_FAULT
+        || scause as usize == SCAUSE_STORE_PAGE_FAULT)
+        && (f.sstatus as usize & SSTATUS_SPP) == 0
+    {
+        // ユーザーモードで発生したページフォルト
+        handle_page_fault(scause as usize, stval as usize, user_pc as usize);
     } else {
         panic!(
             "unexpected trap scause={:x}, stval={:x}, sepc={:x}",
@@ -269,7 +369,60 @@ unsafe fn handle_trap(f: *mut TrapFrame) {
         );
     }
 
-    write_csr!("sepc", user_pc);
+    // ユーザーモードに戻る前に、届いているシグナルを処理する
+    if (f.sstatus as usize & SSTATUS_SPP) == 0 {
+        signal::deliver(f);
+    }
+}
+
+// 期限を過ぎたタイマーを処理して、実行中のプロセスのタイムスライスを消費する
+// システムコールを呼ばずにループし続けるプロセスがいても、タイムスライスを使い切れば他のプロセスが実行される
+fn handle_timer_interrupt() {
+    timer::handle_interrupt();
+    poll_devices();
+    Process::tick();
+}
+
+// デバイスの割り込みを使っていないので、タイマー割り込みのたびに
+// コンソールの入力とディスクの完了を確認して、待っているプロセスを起こす
+fn poll_devices() {
+    console::poll();
+    disk::wake_waiters();
+}
+
+fn handle_page_fault(scause: usize, vaddr: usize, user_pc: usize) {
+    let access = match scause {
+        SCAUSE_INST_PAGE_FAULT => Access::Execute,
+        SCAUSE_LOAD_PAGE_FAULT => Access::Read,
+        _ => Access::Write,
+    };
+
+    unsafe {
+        let current = &mut *PROCESS_TABLE.current;
+        if current.vm.borrow_mut().handle_fault(vaddr, access) {
+            return;
+        }
+
+        // 不正なアクセスをしたプロセスは終了させる
+        crate::common::println!(
+            "process {} killed: page fault at {:x} ({:?}), sepc={:x}",
+            current.pid,
+            vaddr,
+            access,
+            user_pc
+        );
+    }
+
+    Process::exit_current(-1);
+}
+
+// カーネルがユーザー空間のバッファにアクセスできるよう、必要なページを用意する
+// 不正なアドレスが含まれている場合は false を返す
+fn prepare_user_buffer(vaddr: usize, len: usize, write: bool) -> bool {
+    unsafe {
+        let current = &mut *PROCESS_TABLE.current;
+        current.vm.borrow_mut().prepare(vaddr, len, write)
+    }
 }
 
 fn handle_syscall(f: &mut TrapFrame) {
@@ -279,66 +432,431 @@ fn handle_syscall(f: &mut TrapFrame) {
             let a0 = f.a0 as u8 as char;
             crate::common::putchar(a0);
         }
-        SYS_GETCHAR => loop {
-            let c = crate::common::getchar();
-            if c >= 0 {
-                f.a0 = c as i32;
-                break;
-            }
-
-            Process::yield_proc();
-        },
+        SYS_GETCHAR => {
+            f.a0 = match console::getchar() {
+                Some(c) => c as i32,
+                None => -1,
+            };
+        }
         SYS_EXIT => {
+            let status = f.a0;
             unsafe {
                 if PROCESS_TABLE.current.is_null() {
                     panic!("invalid process state");
                 }
 
-                let mut current = &mut (*PROCESS_TABLE.current) as &mut Process;
-                crate::common::println!("process {} exited", current.pid);
-                current.set_state(ProcessState::ProcExit);
+                let current = &mut (*PROCESS_TABLE.current) as &mut Process;
+                crate::common::println!("process {} exited with status {}", current.pid, status);
+            }
+
+            // プロセス全体を終了するので、同じアドレス空間を使う他のスレッドも終了させる
+            // (実行中のスレッドだけを終了するのは SYS_THREAD_EXIT)
+            Process::kill_other_threads();
+            Process::exit_current(status);
+        }
+        SYS_FREE_PAGES => {
+            // 空いている物理ページ数を返す
+            f.a0 = crate::memory::free_page_count() as i32;
+        }
+        SYS_SLABINFO => {
+            // スラブキャッシュの統計情報をコンソールに表示する
+            crate::common::println!("name            objsize per_slab slabs in_use allocs frees");
+            for stats in [
+                process::process_cache_stats(),
+                fs::file_cache_stats(),
+                disk::blk_req_cache_stats(),
+            ] {
+                crate::common::println!(
+                    "{:<15} {:>7} {:>8} {:>5} {:>6} {:>6} {:>5}",
+                    stats.name,
+                    stats.object_size,
+                    stats.objects_per_slab,
+                    stats.slabs,
+                    stats.in_use,
+                    stats.allocs,
+                    stats.frees
+                );
+            }
+        }
+        SYS_FORK => {
+            let child = Process::fork(f);
+            f.a0 = unsafe { (*child).pid };
+        }
+        SYS_THREAD_CREATE => {
+            // 同じアドレス空間を共有するスレッドを作り、スレッドのIDを返す
+            let thread = Process::spawn_thread(f, f.a0 as usize, f.a1 as usize, f.a2 as usize);
+            f.a0 = unsafe { (*thread).pid };
+        }
+        SYS_THREAD_EXIT => {
+            // 実行中のスレッドだけを終了する
+            // アドレス空間は、それを使う最後のスレッドが回収されるときに解放される
+            Process::exit_current(f.a0);
+        }
+        SYS_THREAD_JOIN => {
+            // スレッドが終了するまで待ち、終了ステータスを返す
+            f.a0 = match Process::wait(f.a0) {
+                Some(status) => status,
+                None => -1,
+            };
+        }
+        SYS_FUTEX_WAIT => {
+            f.a0 = futex::futex_wait(f.a0 as usize, f.a1 as u32);
+        }
+        SYS_FUTEX_WAKE => {
+            f.a0 = futex::futex_wake(f.a0 as usize, f.a1 as u32 as usize);
+        }
+        SYS_IPC_SEND => unsafe {
+            // メッセージを送り、返信が届くまで待つ
+            let msg_ptr = f.a1 as usize;
+            let reply_ptr = f.a2 as usize;
+            if !prepare_user_buffer(msg_ptr, IPC_MSG_SIZE, false) {
+                f.a0 = -1;
+                return;
+            }
+
+            let msg = core::ptr::read_unaligned(msg_ptr as *const [u8; IPC_MSG_SIZE]);
+            let mut reply = [0; IPC_MSG_SIZE];
+            f.a0 = ipc::ipc_send(f.a0, &msg, &mut reply);
+
+            // 待っている間にページの状態が変わっているかもしれないので、書き込む直前に用意する
+            if f.a0 == 0 {
+                if !prepare_user_buffer(reply_ptr, IPC_MSG_SIZE, true) {
+                    f.a0 = -1;
+                    return;
+                }
+                core::ptr::write_unaligned(reply_ptr as *mut [u8; IPC_MSG_SIZE], reply);
+            }
+        },
+        SYS_IPC_RECV => unsafe {
+            // メッセージを受け取り、送信したプロセスのIDを返す
+            let msg_ptr = f.a0 as usize;
+            if !prepare_user_buffer(msg_ptr, IPC_MSG_SIZE, true) {
+                f.a0 = -1;
+                return;
+            }
+
+            let mut msg = [0; IPC_MSG_SIZE];
+            let pid = ipc::ipc_recv(&mut msg);
+            if pid < 0 {
+                f.a0 = -1;
+                return;
+            }
+
+            if !prepare_user_buffer(msg_ptr, IPC_MSG_SIZE, true) {
+                f.a0 = -1;
+                return;
+            }
+            core::ptr::write_unaligned(msg_ptr as *mut [u8; IPC_MSG_SIZE], msg);
+            f.a0 = pid;
+        },
+        SYS_IPC_REPLY => unsafe {
+            // メッセージを受け取ったクライアントに返信する
+            let reply_ptr = f.a1 as usize;
+            if !prepare_user_buffer(reply_ptr, IPC_MSG_SIZE, false) {
+                f.a0 = -1;
+                return;
+            }
+
+            let reply = core::ptr::read_unaligned(reply_ptr as *const [u8; IPC_MSG_SIZE]);
+            f.a0 = ipc::ipc_reply(f.a0, &reply);
+        },
+        SYS_PIPE => unsafe {
+            // パイプを作り、読み込み側と書き込み側のファイルディスクリプタを fds[0], fds[1] に書き込む
+            let fds_ptr = f.a0 as usize;
+            if !prepare_user_buffer(fds_ptr, core::mem::size_of::<[i32; 2]>(), true) {
+                f.a0 = -1;
+                return;
+            }
+
+            let current = &mut *PROCESS_TABLE.current;
+            let (reader, writer) = pipe::pipe();
+            let read_fd = match current.fds.alloc(Handle::PipeReader(reader)) {
+                Some(fd) => fd,
+                None => {
+                    f.a0 = -1;
+                    return;
+                }
+            };
+            let write_fd = match current.fds.alloc(Handle::PipeWriter(writer)) {
+                Some(fd) => fd,
+                None => {
+                    current.fds.close(read_fd);
+                    f.a0 = -1;
+                    return;
+                }
+            };
+
+            core::ptr::write_unaligned(fds_ptr as *mut [i32; 2], [read_fd, write_fd]);
+            f.a0 = 0;
+        },
+        SYS_READ => unsafe {
+            // 読み込んだバイト数を返す (0 は EOF)
+            // 一度に読み込むのは 1 ページまで
+            let buf_ptr = f.a1 as usize;
+            let len = (f.a2 as usize).min(PAGE_SIZE);
+            if !prepare_user_buffer(buf_ptr, len, true) {
+                f.a0 = -1;
+                return;
             }
 
-            Process::yield_proc();
-            panic!("unreachable");
+            // 読み込み中に眠ることがあるので、ハンドルを複製してから使う
+            let handle = match (*PROCESS_TABLE.current).fds.get(f.a0) {
+                Some(handle) => handle.clone(),
+                None => {
+                    f.a0 = -1;
+                    return;
+                }
+            };
+
+            let mut buf = alloc::vec![0; len];
+            let read_len = match handle {
+                Handle::Console => {
+                    // 少なくとも1文字は待ち、その後は届いている分だけ読み込む
+                    let mut read_len = 0;
+                    while read_len < len {
+                        let c = if read_len == 0 {
+                            console::getchar()
+                        } else {
+                            console::try_getchar()
+                        };
+                        match c {
+                            Some(c) => buf[read_len] = c,
+                            None => break,
+                        }
+                        read_len += 1;
+                    }
+
+                    // 1文字も読み込まないうちにシグナルで中断された
+                    if read_len == 0 && len > 0 {
+                        f.a0 = -1;
+                        return;
+                    }
+                    read_len
+                }
+                Handle::PipeReader(reader) => match reader.read(&mut buf) {
+                    -1 => {
+                        f.a0 = -1;
+                        return;
+                    }
+                    read_len => read_len as usize,
+                },
+                Handle::PipeWriter(_) => {
+                    f.a0 = -1;
+                    return;
+                }
+            };
+
+            // 待っている間にページの状態が変わっているかもしれないので、書き込む直前に用意する
+            if !prepare_user_buffer(buf_ptr, read_len, true) {
+                f.a0 = -1;
+                return;
+            }
+            core::ptr::copy_nonoverlapping(buf.as_ptr(), buf_ptr as *mut u8, read_len);
+            f.a0 = read_len as i32;
+        },
+        SYS_WRITE => unsafe {
+            // 書き込んだバイト数を返す
+            // 一度に書き込むのは 1 ページまで
+            let buf_ptr = f.a1 as usize;
+            let len = (f.a2 as usize).min(PAGE_SIZE);
+            if !prepare_user_buffer(buf_ptr, len, false) {
+                f.a0 = -1;
+                return;
+            }
+
+            let handle = match (*PROCESS_TABLE.current).fds.get(f.a0) {
+                Some(handle) => handle.clone(),
+                None => {
+                    f.a0 = -1;
+                    return;
+                }
+            };
+
+            let data = core::slice::from_raw_parts(buf_ptr as *const u8, len).to_vec();
+            f.a0 = match handle {
+                Handle::Console => {
+                    for &c in data.iter() {
+                        crate::common::putchar(c as char);
+                    }
+                    len as i32
+                }
+                Handle::PipeWriter(writer) => writer.write(&data),
+                Handle::PipeReader(_) => -1,
+            };
+        },
+        SYS_CLOSE => unsafe {
+            f.a0 = if (*PROCESS_TABLE.current).fds.close(f.a0) {
+                0
+            } else {
+                -1
+            };
+        },
+        SYS_DUP2 => unsafe {
+            // a0 と同じものを a1 にも割り当て、a1 を返す
+            f.a0 = if (*PROCESS_TABLE.current).fds.dup2(f.a0, f.a1) {
+                f.a1
+            } else {
+                -1
+            };
+        },
+        SYS_SHM_CREATE => {
+            // 共有メモリを作り、そのIDを返す
+            f.a0 = shm::shm_create(f.a0 as usize);
+        }
+        SYS_SHM_MAP => {
+            // 共有メモリ a0 を仮想アドレス a1 からマッピングする
+            f.a0 = shm::shm_map(f.a0, f.a1 as usize);
+        }
+        SYS_KILL => {
+            // プロセス a0 にシグナル a1 を送る
+            f.a0 = if Process::kill(f.a0, f.a1 as usize) {
+                0
+            } else {
+                -1
+            };
+        }
+        SYS_SIGNAL => unsafe {
+            // シグナル a0 の動作を a1 に変更し、変更前の動作を返す
+            // ハンドラは a2 に戻るように呼び出す (a2 から SYS_SIGRETURN を呼ぶ)
+            let current = &mut *PROCESS_TABLE.current;
+            f.a0 = match current
+                .signals
+                .set_handler(f.a0 as usize, f.a1 as usize, f.a2 as usize)
+            {
+                Some(old) => old as i32,
+                None => -1,
+            };
+        },
+        SYS_SIGRETURN => {
+            // ハンドラを呼び出す前の状態に戻す (a0 を含む全てのレジスタが元に戻る)
+            if !signal::sigreturn(f) {
+                unsafe {
+                    crate::common::println!(
+                        "process {} killed: bad signal frame",
+                        (*PROCESS_TABLE.current).pid
+                    );
+                }
+                Process::exit_current(-1);
+            }
+        }
+        SYS_GETPID => {
+            f.a0 = unsafe { (*PROCESS_TABLE.current).pid };
+        }
+        SYS_GETPPID => {
+            f.a0 = unsafe { (*PROCESS_TABLE.current).ppid() };
+        }
+        SYS_NICE => unsafe {
+            // nice 値を増減し、変更後の nice 値を返す
+            let current = &mut *PROCESS_TABLE.current;
+            current.set_nice(current.nice().saturating_add(f.a0));
+            f.a0 = current.nice();
+        },
+        SYS_SETPRIORITY => unsafe {
+            // 指定したプロセスの nice 値を設定する (アイドルプロセスとカーネルスレッドは対象外)
+            f.a0 = match PROCESS_TABLE.lookup(f.a0) {
+                Some(proc) if (*proc).is_user_process() => {
+                    (*proc).set_nice(f.a1);
+                    0
+                }
+                _ => -1,
+            };
+        },
+        SYS_SLEEP => {
+            // 指定したミリ秒が経つまで、実行可能な状態から外れる
+            Process::sleep_current(f.a0 as u32 as u64);
         }
+        SYS_WAIT => {
+            // 子プロセスが終了するまで待ち、終了ステータスを返す
+            f.a0 = match Process::wait(f.a0) {
+                Some(status) => status,
+                None => -1,
+            };
+        }
+        SYS_EXEC => unsafe {
+            let path_ptr = f.a0 as *const u8;
+            let path_len = f.a1 as usize;
+
+            if !prepare_user_buffer(path_ptr as usize, path_len, false) {
+                f.a0 = -1;
+                return;
+            }
+
+            let path = core::slice::from_raw_parts(path_ptr, path_len);
+
+            if FILE_SYSTEM.is_null() {
+                panic!("filesystem not found");
+            }
+
+            // ファイルの内容をコピーせずに共有し、ページフォルト時の読み込み元にする
+            // 実行中にファイルが書き換えられても、書き換える前の内容を使い続ける
+            let filesystem = &mut *FILE_SYSTEM;
+            let image = match filesystem.lookup(path) {
+                Some(file) => Image::Shared(Rc::clone(&file.data)),
+                None => {
+                    crate::common::println!(
+                        "file not found: {}",
+                        core::str::from_utf8(path).unwrap_or("?")
+                    );
+                    f.a0 = -1;
+                    return;
+                }
+            };
+
+            let current = &mut *PROCESS_TABLE.current;
+            if !current.exec(image, f) {
+                crate::common::println!(
+                    "exec: invalid executable: {}",
+                    core::str::from_utf8(path).unwrap_or("?")
+                );
+                f.a0 = -1;
+            }
+        },
         SYS_READFILE | SYS_WRITEFILE => unsafe {
             let filename_ptr = f.a0 as *const u8;
             let filename_len = f.a1 as usize;
-            let filename = core::slice::from_raw_parts(filename_ptr, filename_len);
-
             let buf_ptr = f.a2 as *mut u8;
             let buf_len = f.a3 as usize;
 
+            if !prepare_user_buffer(filename_ptr as usize, filename_len, false)
+                || !prepare_user_buffer(buf_ptr as usize, buf_len, a4 == SYS_READFILE)
+            {
+                f.a0 = -1;
+                return;
+            }
+
+            let filename = core::slice::from_raw_parts(filename_ptr, filename_len);
+
             if FILE_SYSTEM.is_null() {
                 panic!("filesystem not found");
             }
 
             let filesystem = &mut *FILE_SYSTEM;
             if let Some(file) = filesystem.lookup(&filename) {
-                if a4 == SYS_WRITEFILE {
+                let len = if a4 == SYS_WRITEFILE {
                     // NOTE: explicitely copy by byte for resolving memory layout
                     // core::ptr::copy_nonoverlapping(buf_ptr, file.data.as_mut_ptr() as *mut u8, buf_len);
-                    crate::memory::memcpy_by_byte(
-                        file.data.as_mut_ptr() as *mut u8,
-                        buf_ptr,
-                        buf_len,
-                    );
-                    file.size = buf_len;
-                    filesystem.flush();
+                    // exec したプロセスが古い内容を使っているかもしれないので、新しい領域に書き込んで差し替える
+                    let mut data = alloc::vec![0; buf_len];
+                    crate::memory::memcpy_by_byte(data.as_mut_ptr(), buf_ptr, buf_len);
+                    file.data = Rc::from(data);
+                    filesystem.mark_dirty();
+                    buf_len
                 } else {
+                    // ファイルサイズを超えて読み込まないようにする
+                    let read_len = buf_len.min(file.data.len());
                     core::ptr::copy_nonoverlapping(
                         file.data.as_ptr() as *const u8,
                         buf_ptr,
-                        buf_len,
+                        read_len,
                     );
-                }
+                    read_len
+                };
 
-                f.a0 = buf_len as i32;
+                f.a0 = len as i32;
             } else {
                 crate::common::println!(
                     "file not found: {}",
-                    core::str::from_utf8(filename).unwrap()
+                    core::str::from_utf8(filename).unwrap_or("?")
                 );
 
                 f.a0 = -1;
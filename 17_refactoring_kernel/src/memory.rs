@@ -1,6 +1,6 @@
 use crate::common::{
-    PAGE_R, PAGE_SIZE, PAGE_TABLE_ENTRY, PAGE_U, PAGE_V, PAGE_W, PAGE_X, USER_BASE,
-    VIRTIO_BLK_PADDR,
+    PAGE_COW, PAGE_FLAGS_MASK, PAGE_R, PAGE_SHARED, PAGE_SIZE, PAGE_TABLE_ENTRY, PAGE_U, PAGE_V,
+    PAGE_W, PAGE_X, VIRTIO_BLK_PADDR,
 };
 
 unsafe extern "C" {
@@ -29,28 +29,651 @@ pub fn memcpy_by_byte(dst: *mut u8, src: *const u8, n: usize) -> *mut u8 {
     dst
 }
 
-// staticを使って前回の割り当て位置を記憶
-static mut NEXT_PADDR: Paddr = 0;
+// __free_ram から __free_ram_end までの領域に含まれる最大ページ数 (kernel.ld の 64MB に対応)
+const FRAMES_MAX: usize = (64 * 1024 * 1024) / PAGE_SIZE;
 
+// バディアロケータが扱う最大のオーダー (2^MAX_ORDER ページ = 4MB)
+pub const MAX_ORDER: usize = 10;
+
+// 空きブロックの先頭ページ以外を表す値
+const ORDER_NONE: u8 = 0xff;
+
+// 空きブロックの先頭ページに埋め込む双方向リストのノード
+// 空きページはカーネルから恒等マッピングでアクセスできるので、ページ自体をリストのノードとして使う
+struct FreeBlock {
+    prev: *mut FreeBlock,
+    next: *mut FreeBlock,
+}
+
+// 物理ページフレームアロケータ (バディシステム)
+// - 2^order ページ単位のブロックをオーダーごとの空きリストで管理する
+// - 割り当て時は大きなブロックを半分ずつ分割し、解放時は空いているバディと再び結合する
+struct FrameAllocator {
+    base: Paddr,                                 // 管理対象領域の先頭アドレス
+    frames: usize,                               // 管理対象のページ数
+    free: usize,                                 // 空きページ数
+    free_lists: [*mut FreeBlock; MAX_ORDER + 1], // オーダーごとの空きリスト
+    orders: [u8; FRAMES_MAX],                    // 空きブロックの先頭ページならそのオーダー
+    shares: [u16; FRAMES_MAX], // ページを共有している追加の参照数 (コピーオンライト用)
+}
+
+static mut FRAME_ALLOCATOR: FrameAllocator = FrameAllocator {
+    base: 0,
+    frames: 0,
+    free: 0,
+    free_lists: [core::ptr::null_mut(); MAX_ORDER + 1],
+    orders: [ORDER_NONE; FRAMES_MAX],
+    shares: [0; FRAMES_MAX],
+};
+
+impl FrameAllocator {
+    fn init(&mut self) {
+        unsafe {
+            let start = &__free_ram as *const u8 as Paddr;
+            let end = &__free_ram_end as *const u8 as Paddr;
+
+            let frames = (end - start) / PAGE_SIZE;
+            if frames > FRAMES_MAX {
+                panic!("too large free ram: {} pages", frames);
+            }
+
+            self.base = start;
+            self.frames = frames;
+            self.free_range(0, frames);
+        }
+    }
+
+    fn frame_index(&self, paddr: Paddr) -> usize {
+        if paddr < self.base || paddr >= self.base + self.frames * PAGE_SIZE {
+            panic!("out of range paddr {:#x}", paddr);
+        }
+
+        (paddr - self.base) / PAGE_SIZE
+    }
+
+    fn block_ptr(&self, index: usize) -> *mut FreeBlock {
+        (self.base + index * PAGE_SIZE) as *mut FreeBlock
+    }
+
+    fn push(&mut self, index: usize, order: usize) {
+        let block = self.block_ptr(index);
+        let head = self.free_lists[order];
+
+        unsafe {
+            (*block).prev = core::ptr::null_mut();
+            (*block).next = head;
+            if !head.is_null() {
+                (*head).prev = block;
+            }
+        }
+
+        self.free_lists[order] = block;
+        self.orders[index] = order as u8;
+        self.free += 1 << order;
+    }
+
+    fn remove(&mut self, index: usize, order: usize) {
+        let block = self.block_ptr(index);
+
+        unsafe {
+            let prev = (*block).prev;
+            let next = (*block).next;
+            if prev.is_null() {
+                self.free_lists[order] = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+
+        self.orders[index] = ORDER_NONE;
+        self.free -= 1 << order;
+    }
+
+    // 2^order ページのブロックを割り当てる
+    fn alloc_block(&mut self, order: usize) -> Option<usize> {
+        if self.frames == 0 {
+            self.init();
+        }
+
+        // 要求を満たせる最小のオーダーの空きブロックを探す
+        let mut current = order;
+        while current <= MAX_ORDER && self.free_lists[current].is_null() {
+            current += 1;
+        }
+        if current > MAX_ORDER {
+            return None;
+        }
+
+        let block = self.free_lists[current];
+        let index = (block as Paddr - self.base) / PAGE_SIZE;
+        self.remove(index, current);
+
+        // 大きすぎるブロックは半分に分割し、後ろ半分 (バディ) を空きリストに戻す
+        while current > order {
+            current -= 1;
+            self.push(index + (1 << current), current);
+        }
+
+        Some(index)
+    }
+
+    // 2^order ページのブロックを解放し、空いているバディと結合する
+    fn free_block(&mut self, index: usize, order: usize) {
+        if self.orders[index] != ORDER_NONE {
+            panic!(
+                "free_pages: double free paddr {:#x}",
+                self.base + index * PAGE_SIZE
+            );
+        }
+
+        let mut index = index;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = index ^ (1 << order);
+            if buddy + (1 << order) > self.frames || self.orders[buddy] != order as u8 {
+                break;
+            }
+
+            self.remove(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+
+        self.push(index, order);
+    }
+
+    // [index, index + count) の範囲を、境界が揃った 2^order ページのブロックに分けて解放する
+    fn free_range(&mut self, index: usize, count: usize) {
+        let mut index = index;
+        let mut remaining = count;
+
+        while remaining > 0 {
+            let mut order = MAX_ORDER;
+            while (index % (1 << order)) != 0 || (1 << order) > remaining {
+                order -= 1;
+            }
+
+            self.free_block(index, order);
+            index += 1 << order;
+            remaining -= 1 << order;
+        }
+    }
+
+    fn alloc(&mut self, n: usize) -> Option<Paddr> {
+        if n == 0 {
+            return None;
+        }
+
+        let order = pages_to_order(n);
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let index = self.alloc_block(order)?;
+
+        // 2の累乗に切り上げた余りのページはすぐに返却する
+        self.free_range(index + n, (1 << order) - n);
+
+        Some(self.base + index * PAGE_SIZE)
+    }
+
+    fn free(&mut self, paddr: Paddr, n: usize) {
+        if !is_aligned(paddr, PAGE_SIZE) {
+            panic!("unaligned paddr {:#x}", paddr);
+        }
+
+        if paddr < self.base || paddr + n * PAGE_SIZE > self.base + self.frames * PAGE_SIZE {
+            panic!("free_pages: out of range paddr {:#x}", paddr);
+        }
+
+        self.free_range((paddr - self.base) / PAGE_SIZE, n);
+    }
+}
+
+// n ページを収めるのに必要な最小のオーダーを返す
+pub const fn pages_to_order(n: usize) -> usize {
+    let mut order = 0;
+    while (1 << order) < n {
+        order += 1;
+    }
+    order
+}
+
+// 2^order ページの連続した物理ページを割り当て、ゼロクリアして返す
+// 返されるアドレスは __free_ram を基準に 2^order ページ境界に揃っている
+pub fn alloc_pages_order(order: usize) -> Paddr {
+    unsafe {
+        let allocator = &mut *(&raw mut FRAME_ALLOCATOR);
+        match allocator.alloc_block(order) {
+            Some(index) => {
+                let paddr = allocator.base + index * PAGE_SIZE;
+                core::ptr::write_bytes(paddr as *mut u8, 0, (1 << order) * PAGE_SIZE);
+                paddr
+            }
+            None => panic!("out of memory"),
+        }
+    }
+}
+
+// n ページ分の連続した物理ページを割り当て、ゼロクリアして返す
 pub fn alloc_pages(n: usize) -> Paddr {
+    let paddr = alloc_pages_uninit(n);
+
     unsafe {
-        // 初期化が必要な場合（最初の呼び出し時）
-        if NEXT_PADDR == 0 {
-            NEXT_PADDR = &__free_ram as *const u8 as Paddr;
+        // 割り当てたメモリをゼロクリア
+        core::ptr::write_bytes(paddr as *mut u8, 0, n * PAGE_SIZE);
+    }
+
+    paddr
+}
+
+// alloc_pages と同じだが、ゼロクリアを行わない
+// すぐに全体を上書きする場合 (ページのコピーなど) に使う
+pub fn alloc_pages_uninit(n: usize) -> Paddr {
+    unsafe {
+        let allocator = &mut *(&raw mut FRAME_ALLOCATOR);
+        match allocator.alloc(n) {
+            Some(paddr) => paddr,
+            None => panic!("out of memory"),
         }
+    }
+}
 
-        let paddr = NEXT_PADDR;
-        NEXT_PADDR += (n as usize) * PAGE_SIZE;
+// alloc_pages で割り当てた n ページを解放する
+pub fn free_pages(paddr: Paddr, n: usize) {
+    unsafe {
+        let allocator = &mut *(&raw mut FRAME_ALLOCATOR);
+        allocator.free(paddr, n);
+    }
+}
 
-        // メモリ範囲チェック
-        if NEXT_PADDR > (&__free_ram_end as *const u8 as Paddr) {
-            panic!("out of memory");
+// 空いている物理ページ数を返す
+pub fn free_page_count() -> usize {
+    unsafe {
+        let allocator = &mut *(&raw mut FRAME_ALLOCATOR);
+        if allocator.frames == 0 {
+            allocator.init();
         }
+        allocator.free
+    }
+}
 
-        // 割り当てたメモリをゼロクリア
-        core::ptr::write_bytes(paddr as *mut u8, 0, (n as usize) * PAGE_SIZE);
+// カーネルヒープ
+// - ページアロケータから確保した領域を、アドレス順に並べた空きブロックのリストで管理する (first-fit)
+// - 解放時は隣接する空きブロックと結合する
+// - 1ページ以上の大きな割り当ては、ページアロケータから直接確保する
+const HEAP_GROW_PAGES: usize = 16; // ヒープが足りなくなったときに一度に追加するページ数
+
+struct HeapBlock {
+    size: usize,          // このブロックのバイト数 (ヘッダを含む)
+    next: *mut HeapBlock, // 次の空きブロック (アドレス順)
+}
+
+const HEAP_BLOCK_SIZE: usize = core::mem::size_of::<HeapBlock>();
+
+struct Heap {
+    free_list: *mut HeapBlock,
+}
+
+static mut HEAP: Heap = Heap {
+    free_list: core::ptr::null_mut(),
+};
+
+impl Heap {
+    // 空きブロックを探して割り当てる
+    fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
+        if let Some(ptr) = self.find_fit(size, align) {
+            return ptr;
+        }
+
+        // 空きが見つからなければページを追加してやり直す
+        let pages = align_up(size + align, PAGE_SIZE) / PAGE_SIZE;
+        let pages = pages.max(HEAP_GROW_PAGES);
+        let paddr = alloc_pages_uninit(pages);
+        self.free(paddr as *mut u8, pages * PAGE_SIZE);
+
+        match self.find_fit(size, align) {
+            Some(ptr) => ptr,
+            None => core::ptr::null_mut(),
+        }
+    }
+
+    fn find_fit(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let mut prev: *mut HeapBlock = core::ptr::null_mut();
+        let mut block = self.free_list;
+
+        unsafe {
+            while !block.is_null() {
+                let block_start = block as usize;
+                let block_end = block_start + (*block).size;
+                let next = (*block).next;
+
+                // 先頭の余りがヘッダより小さくなる場合は、さらに後ろにずらす
+                let mut start = align_up(block_start, align);
+                if start != block_start && start - block_start < HEAP_BLOCK_SIZE {
+                    start = align_up(block_start + HEAP_BLOCK_SIZE, align);
+                }
+
+                let end = start + size;
+                if end <= block_end {
+                    // 使用する部分をリストから外し、前後の余りを空きブロックとして残す
+                    let mut link = next;
+                    if block_end - end > 0 {
+                        let rest = end as *mut HeapBlock;
+                        (*rest).size = block_end - end;
+                        (*rest).next = next;
+                        link = rest;
+                    }
+
+                    if start > block_start {
+                        (*block).size = start - block_start;
+                        (*block).next = link;
+                    } else if prev.is_null() {
+                        self.free_list = link;
+                    } else {
+                        (*prev).next = link;
+                    }
+
+                    return Some(start as *mut u8);
+                }
+
+                prev = block;
+                block = next;
+            }
+        }
+
+        None
+    }
+
+    // 領域を空きリストにアドレス順で挿入し、前後のブロックと結合する
+    fn free(&mut self, ptr: *mut u8, size: usize) {
+        let new_block = ptr as *mut HeapBlock;
+        let mut prev: *mut HeapBlock = core::ptr::null_mut();
+        let mut next = self.free_list;
+
+        unsafe {
+            while !next.is_null() && (next as usize) < (ptr as usize) {
+                prev = next;
+                next = (*next).next;
+            }
+
+            (*new_block).size = size;
+            (*new_block).next = next;
+
+            // 後ろのブロックと結合
+            if !next.is_null() && ptr as usize + size == next as usize {
+                (*new_block).size += (*next).size;
+                (*new_block).next = (*next).next;
+            }
+
+            // 前のブロックと結合
+            if prev.is_null() {
+                self.free_list = new_block;
+            } else if prev as usize + (*prev).size == ptr as usize {
+                (*prev).size += (*new_block).size;
+                (*prev).next = (*new_block).next;
+            } else {
+                (*prev).next = new_block;
+            }
+        }
+    }
+}
+
+// 割り当て単位をヘッダサイズの倍数に揃えることで、分割後の余りが常にヘッダを格納できる大きさになる
+fn heap_layout(layout: core::alloc::Layout) -> (usize, usize) {
+    let size = align_up(layout.size().max(HEAP_BLOCK_SIZE), HEAP_BLOCK_SIZE);
+    let align = layout.align().max(HEAP_BLOCK_SIZE);
+    (size, align)
+}
+
+// alloc クレート (Vec, Box, BTreeMap など) から使われるグローバルアロケータ
+pub struct KernelAllocator;
+
+unsafe impl core::alloc::GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        if layout.size() >= PAGE_SIZE && layout.align() <= PAGE_SIZE {
+            let pages = align_up(layout.size(), PAGE_SIZE) / PAGE_SIZE;
+            return alloc_pages_uninit(pages) as *mut u8;
+        }
+
+        let (size, align) = heap_layout(layout);
+        unsafe {
+            let heap = &mut *(&raw mut HEAP);
+            heap.alloc(size, align)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        if layout.size() >= PAGE_SIZE && layout.align() <= PAGE_SIZE {
+            let pages = align_up(layout.size(), PAGE_SIZE) / PAGE_SIZE;
+            free_pages(ptr as Paddr, pages);
+            return;
+        }
+
+        let (size, _) = heap_layout(layout);
+        unsafe {
+            let heap = &mut *(&raw mut HEAP);
+            heap.free(ptr, size);
+        }
+    }
+}
+
+#[global_allocator]
+static KERNEL_ALLOCATOR: KernelAllocator = KernelAllocator;
+
+// スラブアロケータ
+// - 同じ型のオブジェクトを1ページ (スラブ) にまとめて詰め込み、型ごとのキャッシュで管理する
+// - スラブの先頭にヘッダを置き、空きオブジェクトはその先頭ワードを使った単方向リストでつなぐ
+// - オブジェクトのアドレスをページ境界に切り下げると、所属するスラブのヘッダが得られる
+struct Slab {
+    next: *mut Slab,       // 同じキャッシュに属する次のスラブ
+    free: *mut SlabObject, // このスラブ内の空きオブジェクト
+    in_use: usize,         // このスラブで使用中のオブジェクト数
+}
+
+struct SlabObject {
+    next: *mut SlabObject,
+}
+
+const SLAB_HEADER_SIZE: usize = core::mem::size_of::<Slab>();
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,      // キャッシュ名
+    pub object_size: usize,      // 1オブジェクトあたりのバイト数 (パディングを含む)
+    pub objects_per_slab: usize, // 1スラブに格納できるオブジェクト数
+    pub slabs: usize,            // 確保しているスラブ数
+    pub in_use: usize,           // 使用中のオブジェクト数
+    pub allocs: usize,           // これまでの割り当て回数
+    pub frees: usize,            // これまでの解放回数
+}
+
+pub struct SlabCache<T> {
+    ctor: fn() -> T, // 割り当てたオブジェクトを初期化するコンストラクタ
+    slabs: *mut Slab,
+    stats: SlabStats,
+}
+
+impl<T> SlabCache<T> {
+    const OBJECT_ALIGN: usize = if core::mem::align_of::<T>() > core::mem::align_of::<SlabObject>()
+    {
+        core::mem::align_of::<T>()
+    } else {
+        core::mem::align_of::<SlabObject>()
+    };
+    const OBJECT_SIZE: usize = align_up(
+        if core::mem::size_of::<T>() > core::mem::size_of::<SlabObject>() {
+            core::mem::size_of::<T>()
+        } else {
+            core::mem::size_of::<SlabObject>()
+        },
+        Self::OBJECT_ALIGN,
+    );
+    const OBJECTS_OFFSET: usize = align_up(SLAB_HEADER_SIZE, Self::OBJECT_ALIGN);
+    const OBJECTS_PER_SLAB: usize = (PAGE_SIZE - Self::OBJECTS_OFFSET) / Self::OBJECT_SIZE;
+
+    pub const fn new(name: &'static str, ctor: fn() -> T) -> Self {
+        SlabCache {
+            ctor,
+            slabs: core::ptr::null_mut(),
+            stats: SlabStats {
+                name,
+                object_size: Self::OBJECT_SIZE,
+                objects_per_slab: Self::OBJECTS_PER_SLAB,
+                slabs: 0,
+                in_use: 0,
+                allocs: 0,
+                frees: 0,
+            },
+        }
+    }
+
+    // オブジェクトを1つ割り当て、コンストラクタで初期化して返す
+    pub fn alloc(&mut self) -> *mut T {
+        if Self::OBJECTS_PER_SLAB == 0 {
+            panic!("slab: {}: too large object", self.stats.name);
+        }
+
+        unsafe {
+            // 空きのあるスラブを探し、なければ新しく作る
+            let mut slab = self.slabs;
+            while !slab.is_null() && (*slab).free.is_null() {
+                slab = (*slab).next;
+            }
+            if slab.is_null() {
+                slab = self.grow();
+            }
+
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+
+            self.stats.in_use += 1;
+            self.stats.allocs += 1;
+
+            let ptr = object as *mut T;
+            ptr.write((self.ctor)());
+            ptr
+        }
+    }
+
+    // alloc で割り当てたオブジェクトを破棄し、キャッシュに戻す
+    pub fn free(&mut self, ptr: *mut T) {
+        unsafe {
+            let slab = (ptr as usize & !(PAGE_SIZE - 1)) as *mut Slab;
+            if (*slab).in_use == 0 {
+                panic!("slab: {}: double free {:p}", self.stats.name, ptr);
+            }
+
+            core::ptr::drop_in_place(ptr);
+
+            let object = ptr as *mut SlabObject;
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+
+            self.stats.in_use -= 1;
+            self.stats.frees += 1;
+
+            // 空になったスラブは、他にもスラブがあればページアロケータに返す
+            if (*slab).in_use == 0 && self.stats.slabs > 1 {
+                self.release(slab);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.stats
+    }
+
+    // 新しいスラブを確保して、全てのオブジェクトを空きリストにつなぐ
+    fn grow(&mut self) -> *mut Slab {
+        let page = alloc_pages(1);
+        let slab = page as *mut Slab;
+
+        unsafe {
+            (*slab).in_use = 0;
+            (*slab).free = core::ptr::null_mut();
+            for i in (0..Self::OBJECTS_PER_SLAB).rev() {
+                let object =
+                    (page + Self::OBJECTS_OFFSET + i * Self::OBJECT_SIZE) as *mut SlabObject;
+                (*object).next = (*slab).free;
+                (*slab).free = object;
+            }
+
+            (*slab).next = self.slabs;
+        }
+
+        self.slabs = slab;
+        self.stats.slabs += 1;
+        slab
+    }
+
+    fn release(&mut self, slab: *mut Slab) {
+        unsafe {
+            if self.slabs == slab {
+                self.slabs = (*slab).next;
+            } else {
+                let mut prev = self.slabs;
+                while (*prev).next != slab {
+                    prev = (*prev).next;
+                }
+                (*prev).next = (*slab).next;
+            }
+        }
+
+        free_pages(slab as Paddr, 1);
+        self.stats.slabs -= 1;
+    }
+}
+
+// ページの参照カウント
+// - 割り当てたページは1つの所有者から参照されている状態から始まる
+// - fork などで別のページテーブルからも参照させる場合は share_page で参照を増やす
+// - release_page で参照を減らし、最後の参照がなくなったときにページを解放する
+
+// ページへの参照を1つ増やす
+pub fn share_page(paddr: Paddr) {
+    unsafe {
+        let allocator = &mut *(&raw mut FRAME_ALLOCATOR);
+        let index = allocator.frame_index(paddr);
+        allocator.shares[index] += 1;
+    }
+}
+
+// ページへの参照を1つ減らし、誰からも参照されなくなったら解放する
+pub fn release_page(paddr: Paddr) {
+    unsafe {
+        let allocator = &mut *(&raw mut FRAME_ALLOCATOR);
+        let index = allocator.frame_index(paddr);
+        if allocator.shares[index] > 0 {
+            allocator.shares[index] -= 1;
+        } else {
+            allocator.free(paddr, 1);
+        }
+    }
+}
+
+// ページが複数の所有者から参照されているかを返す
+pub fn is_page_shared(paddr: Paddr) -> bool {
+    unsafe {
+        let allocator = &*(&raw const FRAME_ALLOCATOR);
+        let index = allocator.frame_index(paddr);
+        allocator.shares[index] > 0
+    }
+}
+
+// [start, end) が、全てのプロセスで共通にマッピングしているカーネル領域と重なるかを返す
+pub fn is_kernel_region(start: Vaddr, end: Vaddr) -> bool {
+    unsafe {
+        let kernel_base_addr = &__kernel_base as *const u8 as Vaddr;
+        let free_ram_end_addr = &__free_ram_end as *const u8 as Vaddr;
 
-        paddr
+        (start < free_ram_end_addr && kernel_base_addr < end)
+            || (start < VIRTIO_BLK_PADDR + PAGE_SIZE && VIRTIO_BLK_PADDR < end)
     }
 }
 
@@ -62,13 +685,19 @@ pub const fn align_up(value: usize, align: usize) -> usize {
     ((value + align - 1) / align) * align
 }
 
+pub const fn align_down(value: usize, align: usize) -> usize {
+    (value / align) * align
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct PageTable {
     pub addr: Paddr,
 }
 
 impl PageTable {
-    pub fn new(image: *const u8, image_size: usize) -> Self {
+    // カーネル領域とMMIO領域だけをマッピングしたページテーブルを作る
+    // ユーザー領域のページは、ページフォルト時に vm::AddressSpace が必要に応じてマッピングする
+    pub fn new() -> Self {
         unsafe {
             let kernel_base_addr = &__kernel_base as *const u8 as Paddr;
             let free_ram_end_addr = &__free_ram_end as *const u8 as Paddr;
@@ -89,37 +718,11 @@ impl PageTable {
             // 各プロセスのページテーブルに virtio-blk のMMIO領域をマップ
             page_table.map_page(VIRTIO_BLK_PADDR, VIRTIO_BLK_PADDR, PAGE_R | PAGE_W);
 
-            // image を memory に展開
-            let mut offset: usize = 0;
-            while offset < image_size {
-                let page = alloc_pages(1);
-
-                // コピーするデータがページサイズより小さい場合を考慮
-                let remaining = image_size - offset;
-                let copy_size = if remaining >= PAGE_SIZE {
-                    PAGE_SIZE
-                } else {
-                    remaining
-                };
-
-                // 確保したページにデータをコピー
-                core::ptr::copy_nonoverlapping(image.add(offset), page as *mut u8, copy_size);
-
-                // ページテーブルにマッピング
-                page_table.map_page(
-                    USER_BASE + offset as Vaddr,
-                    page,
-                    PAGE_U | PAGE_R | PAGE_W | PAGE_X,
-                );
-
-                offset += PAGE_SIZE;
-            }
-
             page_table
         }
     }
 
-    fn map_page(&mut self, vaddr: Vaddr, paddr: Paddr, flags: usize) {
+    pub fn map_page(&mut self, vaddr: Vaddr, paddr: Paddr, flags: usize) {
         if !is_aligned(vaddr, PAGE_SIZE) {
             panic!("unaligned vaddr {:#x}", vaddr);
         }
@@ -150,6 +753,101 @@ impl PageTable {
         }
     }
 
+    // 仮想アドレスに対応する2段目のページテーブルエントリを返す
+    // 2段目のページテーブルが存在しない場合は None
+    pub fn lookup(&mut self, vaddr: Vaddr) -> Option<&mut usize> {
+        let table1 = self.as_mut_slice();
+
+        let vpn1 = (vaddr >> 22) & 0x3ff;
+        if (table1[vpn1] & PAGE_V) == 0 {
+            return None;
+        }
+
+        let vpn0 = (vaddr >> 12) & 0x3ff;
+        let table0_ptr = ((table1[vpn1] >> 10) * PAGE_SIZE) as *mut usize;
+        unsafe {
+            let table0 = core::slice::from_raw_parts_mut(table0_ptr, PAGE_TABLE_ENTRY);
+            Some(&mut table0[vpn0])
+        }
+    }
+
+    // 仮想アドレスを物理アドレスに変換する
+    pub fn translate(&mut self, vaddr: Vaddr) -> Option<Paddr> {
+        let pte = *self.lookup(vaddr)?;
+        if (pte & PAGE_V) == 0 {
+            return None;
+        }
+
+        Some((pte >> 10) * PAGE_SIZE + (vaddr % PAGE_SIZE))
+    }
+
+    // ユーザーページのマッピングを全て dst にコピーし、物理ページを共有する (fork用)
+    // 書き込み可能なページは両方のページテーブルで読み取り専用 + PAGE_COW にし、
+    // 書き込まれたときにページフォルトでコピーする
+    // 共有メモリのページ (PAGE_SHARED) は、書き込み可能なまま共有する
+    pub fn share_user_pages(&mut self, dst: &mut PageTable) {
+        let table1 = self.as_mut_slice();
+
+        for vpn1 in 0..PAGE_TABLE_ENTRY {
+            if (table1[vpn1] & PAGE_V) == 0 {
+                continue;
+            }
+
+            let table0_paddr = (table1[vpn1] >> 10) * PAGE_SIZE;
+            let table0 = unsafe {
+                core::slice::from_raw_parts_mut(table0_paddr as *mut usize, PAGE_TABLE_ENTRY)
+            };
+
+            for vpn0 in 0..PAGE_TABLE_ENTRY {
+                let mut pte = table0[vpn0];
+                if (pte & PAGE_V) == 0 || (pte & PAGE_U) == 0 {
+                    continue;
+                }
+
+                if (pte & PAGE_W) != 0 && (pte & PAGE_SHARED) == 0 {
+                    pte = (pte & !PAGE_W) | PAGE_COW;
+                    table0[vpn0] = pte;
+                }
+
+                let paddr = (pte >> 10) * PAGE_SIZE;
+                share_page(paddr);
+                dst.map_page((vpn1 << 22) | (vpn0 << 12), paddr, pte & PAGE_FLAGS_MASK);
+            }
+        }
+    }
+
+    // ページテーブルを破棄する
+    // ユーザーページ (PAGE_U) の物理ページと、1段目・2段目のページテーブル自体を解放する
+    // カーネル領域やMMIO領域は恒等マッピングで共有しているので解放しない
+    pub fn free(&mut self) {
+        let table1 = self.as_mut_slice();
+
+        for vpn1 in 0..PAGE_TABLE_ENTRY {
+            if (table1[vpn1] & PAGE_V) == 0 {
+                continue;
+            }
+
+            let table0_paddr = (table1[vpn1] >> 10) * PAGE_SIZE;
+            let table0 = unsafe {
+                core::slice::from_raw_parts_mut(table0_paddr as *mut usize, PAGE_TABLE_ENTRY)
+            };
+
+            for vpn0 in 0..PAGE_TABLE_ENTRY {
+                let pte = table0[vpn0];
+                if (pte & PAGE_V) != 0 && (pte & PAGE_U) != 0 {
+                    release_page((pte >> 10) * PAGE_SIZE);
+                }
+                table0[vpn0] = 0;
+            }
+
+            free_pages(table0_paddr, 1);
+            table1[vpn1] = 0;
+        }
+
+        free_pages(self.addr, 1);
+        self.addr = 0;
+    }
+
     fn as_mut_slice(&mut self) -> &mut [usize] {
         let base_ptr = self.addr as *mut usize;
 
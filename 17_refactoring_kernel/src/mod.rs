@@ -1,5 +1,16 @@
 pub mod common;
+pub mod console;
 pub mod disk;
+pub mod elf;
+pub mod fd;
 pub mod fs;
+pub mod futex;
+pub mod ipc;
 pub mod memory;
+pub mod pipe;
 pub mod process;
+pub mod shm;
+pub mod signal;
+pub mod timer;
+pub mod vm;
+pub mod wait;
@@ -0,0 +1,142 @@
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+
+use crate::common::PIPE_SIZE;
+use crate::signal;
+use crate::wait::WaitQueue;
+
+/*
+パイプ
+- カーネル内のリングバッファを、読み込み側 (PipeReader) と書き込み側 (PipeWriter) で共有する
+- 読み込み側と書き込み側の数を数え、全ての書き込み側が閉じられたら読み込み側に EOF を返す
+- PipeReader / PipeWriter を複製 (fork や dup2) すると数が増え、破棄 (close や exit) すると減る
+*/
+pub struct Pipe {
+    buf: VecDeque<u8>,        // 書き込まれて、まだ読み込まれていないデータ
+    readers: usize,           // 読み込み側の数
+    writers: usize,           // 書き込み側の数
+    read_waiters: WaitQueue,  // データが書き込まれるのを待っているプロセス
+    write_waiters: WaitQueue, // バッファに空きができるのを待っているプロセス
+}
+
+pub struct PipeReader(Rc<RefCell<Pipe>>);
+pub struct PipeWriter(Rc<RefCell<Pipe>>);
+
+// パイプを作り、読み込み側と書き込み側を返す
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Rc::new(RefCell::new(Pipe {
+        buf: VecDeque::with_capacity(PIPE_SIZE),
+        readers: 1,
+        writers: 1,
+        read_waiters: WaitQueue::new(),
+        write_waiters: WaitQueue::new(),
+    }));
+
+    (PipeReader(Rc::clone(&pipe)), PipeWriter(pipe))
+}
+
+impl PipeReader {
+    // パイプから最大 buf.len() バイト読み込み、読み込んだバイト数を返す
+    // データがなければ書き込まれるまで眠り、全ての書き込み側が閉じられていれば 0 (EOF) を返す
+    // 待っている間にシグナルが届いた場合は -1 を返す
+    pub fn read(&self, buf: &mut [u8]) -> i32 {
+        loop {
+            {
+                let mut pipe = self.0.borrow_mut();
+                if !pipe.buf.is_empty() {
+                    let len = buf.len().min(pipe.buf.len());
+                    for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(..len)) {
+                        *dst = src;
+                    }
+                    pipe.write_waiters.wake_all();
+                    return len as i32;
+                }
+
+                if pipe.writers == 0 {
+                    return 0;
+                }
+            }
+
+            if signal::interrupted() {
+                return -1;
+            }
+
+            // 眠っている間に書き込み側がパイプを使えるよう、借用を外してから眠る
+            unsafe { (*self.0.as_ptr()).read_waiters.sleep() };
+        }
+    }
+}
+
+impl PipeWriter {
+    // パイプに data を全て書き込み、書き込んだバイト数を返す
+    // バッファがいっぱいなら空くまで眠り、読み込み側が全て閉じられているかシグナルが届いた場合は書き込みをやめる
+    // 1バイトも書き込めなかった場合は -1 を返す
+    pub fn write(&self, data: &[u8]) -> i32 {
+        let mut written = 0;
+        while written < data.len() {
+            {
+                let mut pipe = self.0.borrow_mut();
+                if pipe.readers == 0 {
+                    break;
+                }
+
+                let len = (PIPE_SIZE - pipe.buf.len()).min(data.len() - written);
+                if len > 0 {
+                    pipe.buf.extend(&data[written..written + len]);
+                    written += len;
+                    pipe.read_waiters.wake_all();
+                    continue;
+                }
+            }
+
+            if signal::interrupted() {
+                break;
+            }
+
+            unsafe { (*self.0.as_ptr()).write_waiters.sleep() };
+        }
+
+        if written == 0 && !data.is_empty() {
+            -1
+        } else {
+            written as i32
+        }
+    }
+}
+
+impl Clone for PipeReader {
+    fn clone(&self) -> Self {
+        self.0.borrow_mut().readers += 1;
+        PipeReader(Rc::clone(&self.0))
+    }
+}
+
+impl Clone for PipeWriter {
+    fn clone(&self) -> Self {
+        self.0.borrow_mut().writers += 1;
+        PipeWriter(Rc::clone(&self.0))
+    }
+}
+
+impl Drop for PipeReader {
+    // 最後の読み込み側が閉じられたら、書き込みを待っているプロセスを起こす
+    fn drop(&mut self) {
+        let mut pipe = self.0.borrow_mut();
+        pipe.readers -= 1;
+        if pipe.readers == 0 {
+            pipe.write_waiters.wake_all();
+        }
+    }
+}
+
+impl Drop for PipeWriter {
+    // 最後の書き込み側が閉じられたら、読み込みを待っているプロセスを起こす (EOF を返すため)
+    fn drop(&mut self) {
+        let mut pipe = self.0.borrow_mut();
+        pipe.writers -= 1;
+        if pipe.writers == 0 {
+            pipe.read_waiters.wake_all();
+        }
+    }
+}
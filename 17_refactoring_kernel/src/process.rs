@@ -1,61 +1,249 @@
-use crate::common::{PAGE_SIZE, PROCS_MAX, SATP_SV32, SSTATUS_SPIE, SSTATUS_SUM, USER_BASE};
-use crate::memory::{PageTable, Vaddr};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use crate::common::{
+    KERNEL_STACK_SIZE, NICE_MAX, NICE_MIN, PAGE_SIZE, SATP_SV32, SCHED_BOOST_INTERVAL,
+    SCHED_LEVELS, SIGCHLD, SIGKILL, SSTATUS_SPIE, SSTATUS_SUM,
+};
+use crate::fd::FdTable;
+use crate::ipc::{Endpoint, ipc_abort};
+use crate::memory::{SlabCache, SlabStats, Vaddr};
+use crate::shm;
+use crate::signal::{self, Signals};
+use crate::timer;
+use crate::vm::{AddressSpace, Image};
+use crate::wait::WaitQueue;
+use crate::{TrapFrame, trap_return};
 
 // 現在実行中のプロセスとアイドルプロセスのグローバル変数
 pub struct ProcessTable {
     pub current: *mut Process,
     pub idol: *mut Process,
-    processes: [Process; PROCS_MAX],
+    pub init: *mut Process, // 最初のユーザープロセス (親を失ったプロセスの引き取り先)
+    processes: Vec<*mut Process>,
+    pids: BTreeMap<i32, *mut Process>, // プロセスIDからプロセス管理構造体を引くための表
+    next_pid: i32,                     // 次に割り当てるプロセスID (単調増加し、再利用しない)
+    ticks: usize,                      // 起動してからのタイマー割り込みの回数
 }
 
 pub static mut PROCESS_TABLE: ProcessTable = ProcessTable {
     current: core::ptr::null_mut(),
     idol: core::ptr::null_mut(),
-    processes: [Process {
-        pid: 0,
-        state: ProcessState::Unused,
-        sp: 0,
-        page_table: PageTable { addr: 0 },
-        stack: [0; 8192],
-    }; PROCS_MAX],
+    init: core::ptr::null_mut(),
+    processes: Vec::new(),
+    pids: BTreeMap::new(),
+    next_pid: 0,
+    ticks: 0,
 };
 
-#[derive(Clone, Copy)]
+impl ProcessTable {
+    // プロセスIDから、使用中のプロセス管理構造体を探す
+    pub fn lookup(&self, pid: i32) -> Option<*mut Process> {
+        self.pids.get(&pid).copied()
+    }
+}
+
+// プロセス管理構造体 (Process Control Block) のスラブキャッシュ
+static mut PROCESS_CACHE: SlabCache<Process> = SlabCache::new("process", Process::empty);
+
+pub fn process_cache_stats() -> SlabStats {
+    unsafe { (*(&raw const PROCESS_CACHE)).stats() }
+}
+
 pub struct Process {
-    pub pid: i32,            // プロセスID
-    pub state: ProcessState, // プロセスの状態
-    sp: Vaddr,               // コンテキストスイッチ時のスタックポインタ
-    page_table: PageTable,   // 動的サイズの配列へのポインタ
-    stack: [u8; 8192],       // カーネルスタック
+    pub pid: i32,                        // プロセスID
+    pub state: ProcessState,             // プロセスの状態
+    parent: *mut Process,                // 親プロセス (親がいない場合は null)
+    children: Vec<*mut Process>,         // 子プロセス
+    orphaned: bool,                      // 親が先に終了して、最初のユーザープロセスに引き取られたか
+    exit_status: i32,                    // 終了ステータス (Zombie の間だけ意味を持つ)
+    nice: i32,                           // nice 値 (NICE_MIN..=NICE_MAX、小さいほど優先される)
+    level: usize,                        // スケジューラのレベル (0 が最も優先度が高い)
+    ticks: usize,          // 現在のレベルで使ったタイムスライス (タイマー割り込みの回数)
+    child_exit: WaitQueue, // 子プロセスの終了を待っているプロセス (自分自身)
+    kthread: Option<(fn(usize), usize)>, // カーネルスレッドの場合は、実行する関数と引数
+    pub ipc: Endpoint,     // プロセス間通信 (IPC) の状態
+    pub fds: FdTable,      // ファイルディスクリプタの表
+    pub signals: Signals,  // シグナルの状態
+    sp: Vaddr,             // コンテキストスイッチ時のスタックポインタ
+    pub vm: Rc<RefCell<AddressSpace>>, // アドレス空間 (ページテーブルとユーザー領域)
+    stack: Box<[u8]>,      // カーネルスタック
 }
 
 impl Process {
-    pub fn new(image: *const u8, image_size: usize) -> *mut Self {
-        // 空いているプロセス管理構造体(Process Control Block)を探す
-        let (proc_index, proc) = unsafe {
-            let mut found_index = None;
-            let mut found_proc = None;
-
-            for i in 0..PROCS_MAX {
-                if PROCESS_TABLE.processes[i].state == ProcessState::Unused {
-                    found_index = Some(i);
-                    found_proc = Some(&mut PROCESS_TABLE.processes[i]);
-                    break;
-                }
-            }
+    pub fn new(image: Image) -> *mut Self {
+        let vm = match AddressSpace::new(image) {
+            Some(vm) => vm,
+            None => panic!("invalid user image"),
+        };
 
-            // 空きスロットがなければパニック
-            match (found_index, found_proc) {
-                (Some(idx), Some(p)) => (idx, p),
-                _ => panic!("no free process slots"),
-            }
+        let (pid, proc) = Process::alloc_slot();
+
+        unsafe {
+            let stack_top = proc.stack.as_mut_ptr().add(proc.stack.len());
+            let sp = Process::push_switch_frame(stack_top as *mut usize, user_entry as usize);
+            proc.setup(
+                pid,
+                core::ptr::null_mut(),
+                None,
+                Rc::new(RefCell::new(vm)),
+                sp,
+            );
+        }
+
+        proc as *mut Process
+    }
+
+    // 実行中のプロセスを複製する (fork)
+    // 子プロセスは親プロセスと同じ TrapFrame から再開し、戻り値 (a0) だけが 0 になる
+    pub fn fork(frame: &TrapFrame) -> *mut Self {
+        let parent = unsafe { &mut *PROCESS_TABLE.current };
+        let (pid, proc) = Process::alloc_slot();
+
+        unsafe {
+            // カーネルスタックの末尾に TrapFrame を置き、trap_return() でユーザーモードに戻れるようにする
+            let stack_top = proc.stack.as_mut_ptr().add(proc.stack.len());
+            let child_frame = (stack_top as *mut TrapFrame).sub(1);
+            child_frame.write(*frame);
+            (*child_frame).a0 = 0;
+
+            let sp = Process::push_switch_frame(child_frame as *mut usize, trap_return as usize);
+            let vm = Rc::new(RefCell::new(parent.vm.borrow_mut().fork()));
+            proc.setup(pid, parent, None, vm, sp);
+        }
+
+        proc as *mut Process
+    }
+
+    // 実行中のプロセスのアドレス空間を、新しいイメージで置き換える (exec)
+    // 成功した場合は TrapFrame をエントリポイントから始まるように書き換え、
+    // イメージの形式が正しくない場合は何もせずに false を返す
+    // プロセス全体が新しいプログラムに置き換わるので、同じアドレス空間を使う他のスレッドは終了させる
+    pub fn exec(&mut self, image: Image, frame: &mut TrapFrame) -> bool {
+        let vm = match AddressSpace::new(image) {
+            Some(vm) => vm,
+            None => return false,
         };
 
-        // Process::switch_context() で復帰できるように、スタックに呼び出し先保存レジスタを積む
+        Process::kill_other_threads();
+
+        // 古いページテーブルを解放する前に、新しいページテーブルに切り替える
+        // カーネル領域のマッピングはどちらも同じなので、切り替えてもカーネルの実行は続けられる
+        // 他のスレッドは終了するまで古いアドレス空間を使うので、最後のスレッドの場合だけ解放する
+        vm.activate();
+        let entry = vm.entry;
+        let old_vm = core::mem::replace(&mut self.vm, Rc::new(RefCell::new(vm)));
+        if let Ok(old_vm) = Rc::try_unwrap(old_vm) {
+            old_vm.into_inner().free();
+        }
+
+        // 登録されていたハンドラは新しいプログラムには存在しない
+        self.signals.reset_handlers();
+
+        // レジスタを全てクリアして、エントリポイントから実行を始める
+        *frame = TrapFrame {
+            sepc: entry as u32,
+            sstatus: frame.sstatus,
+            ..TrapFrame::default()
+        };
+
+        true
+    }
+
+    // 実行中のプロセスと同じアドレス空間を共有するスレッドを作る
+    // スレッドはユーザーモードの entry から、スタック stack、引数 arg (a0) で実行を始める
+    // 作ったプロセスの子として扱うので、Process::wait() で終了を待てる
+    pub fn spawn_thread(frame: &TrapFrame, entry: Vaddr, stack: Vaddr, arg: usize) -> *mut Self {
+        let parent = unsafe { &mut *PROCESS_TABLE.current };
+        let (pid, proc) = Process::alloc_slot();
+
         unsafe {
-            let stack_top = proc.stack.as_ptr().add(proc.stack.len());
-            let mut sp = stack_top as *mut usize;
+            // カーネルスタックの末尾に TrapFrame を置き、trap_return() で entry から始まるようにする
+            let stack_top = proc.stack.as_mut_ptr().add(proc.stack.len());
+            let thread_frame = (stack_top as *mut TrapFrame).sub(1);
+            thread_frame.write(TrapFrame {
+                gp: frame.gp,
+                sp: stack as i32,
+                a0: arg as i32,
+                sepc: entry as u32,
+                sstatus: frame.sstatus,
+                ..TrapFrame::default()
+            });
+
+            let sp = Process::push_switch_frame(thread_frame as *mut usize, trap_return as usize);
+            let vm = Rc::clone(&parent.vm);
+            proc.setup(pid, parent, None, vm, sp);
+        }
+
+        proc as *mut Process
+    }
+
+    // alloc_slot() で割り当てたプロセス管理構造体を、実行可能なプロセスとして初期化する
+    // parent がいればその子プロセスにして、nice 値・ファイルディスクリプタ・シグナルの設定を引き継ぐ
+    // parent がいなければ既定の設定にする (ユーザープロセスの標準入出力はコンソールにつなぐ)
+    fn setup(
+        &mut self,
+        pid: i32,
+        parent: *mut Process,
+        kthread: Option<(fn(usize), usize)>,
+        vm: Rc<RefCell<AddressSpace>>,
+        sp: *mut usize,
+    ) {
+        self.pid = pid;
+        self.state = ProcessState::Runnable;
+        self.parent = parent;
+        self.children.clear();
+        self.exit_status = 0;
+        self.orphaned = false;
+        self.kthread = kthread;
+        self.ipc = Endpoint::new();
+        self.vm = vm;
+        self.sp = sp as Vaddr;
+
+        if parent.is_null() {
+            self.set_nice(0);
+            self.fds = if self.kthread.is_some() {
+                FdTable::new()
+            } else {
+                FdTable::with_console()
+            };
+            self.signals = Signals::new();
+        } else {
+            unsafe {
+                self.set_nice((*parent).nice);
+                self.fds = (*parent).fds.clone();
+                self.signals = (*parent).signals.inherit();
+                (*parent).children.push(self as *mut Process);
+            }
+        }
+    }
+
+    // スラブキャッシュからプロセス管理構造体(Process Control Block)を割り当て、新しいプロセスIDを割り当てる
+    // 割り当てた構造体は、終了後に Process::destroy() でキャッシュに返す
+    fn alloc_slot() -> (i32, &'static mut Process) {
+        unsafe {
+            let proc = (*(&raw mut PROCESS_CACHE)).alloc();
+            let processes = &mut *(&raw mut PROCESS_TABLE.processes);
+            processes.push(proc);
+
+            let pid = PROCESS_TABLE.next_pid;
+            PROCESS_TABLE.next_pid += 1;
+            let pids = &mut *(&raw mut PROCESS_TABLE.pids);
+            pids.insert(pid, proc);
+
+            (pid, &mut *proc)
+        }
+    }
 
+    // Process::switch_context() で復帰できるように、スタックに呼び出し先保存レジスタを積む
+    // ra には switch_context() から最初に戻る先を設定する
+    unsafe fn push_switch_frame(sp: *mut usize, ra: usize) -> *mut usize {
+        let mut sp = sp;
+
+        unsafe {
             sp = sp.sub(1);
             *sp = 0; // s11
             sp = sp.sub(1);
@@ -81,21 +269,334 @@ impl Process {
             sp = sp.sub(1);
             *sp = 0; // s0
             sp = sp.sub(1);
-            *sp = user_entry as usize; // ra
+            *sp = ra; // ra
+        }
 
-            let page_table = PageTable::new(image, image_size);
-            // プロセス情報を更新
-            proc.pid = (proc_index + 1) as i32;
-            proc.state = ProcessState::Runnable;
-            proc.page_table = page_table;
-            proc.sp = sp as Vaddr;
+        sp
+    }
+
+    // スラブキャッシュのコンストラクタ
+    fn empty() -> Self {
+        Process {
+            pid: 0,
+            state: ProcessState::Unused,
+            parent: core::ptr::null_mut(),
+            children: Vec::new(),
+            exit_status: 0,
+            orphaned: false,
+            nice: 0,
+            level: 0,
+            ticks: 0,
+            child_exit: WaitQueue::new(),
+            kthread: None,
+            ipc: Endpoint::new(),
+            fds: FdTable::new(),
+            signals: Signals::new(),
+            sp: 0,
+            vm: Rc::new(RefCell::new(AddressSpace::empty())),
+            stack: alloc::vec![0; KERNEL_STACK_SIZE].into_boxed_slice(),
         }
+    }
 
-        proc as *mut Process
+    // 終了したプロセスのアドレス空間を解放し、プロセス管理構造体をスラブキャッシュに返す
+    // 自分自身のページテーブルとカーネルスタックを使っている間は呼べないので、
+    // 他のプロセスに切り替わった後で Process::reap() から呼ばれる
+    fn destroy(proc: *mut Process) {
+        unsafe {
+            // 親プロセスの子プロセス一覧から外す
+            let parent = (*proc).parent;
+            if !parent.is_null() {
+                (*parent).children.retain(|&c| c != proc);
+            }
+
+            let pids = &mut *(&raw mut PROCESS_TABLE.pids);
+            pids.remove(&(*proc).pid);
+            let processes = &mut *(&raw mut PROCESS_TABLE.processes);
+            processes.retain(|&p| p != proc);
+            if PROCESS_TABLE.init == proc {
+                PROCESS_TABLE.init = core::ptr::null_mut();
+            }
+
+            // カーネルスタックやファイルディスクリプタなどは、キャッシュに返すときに破棄される
+            (*proc).release_vm();
+            (*(&raw mut PROCESS_CACHE)).free(proc);
+        }
     }
 
-    pub fn set_pid(&mut self, pid: i32) {
-        self.pid = pid;
+    // アドレス空間への参照を手放す
+    // 同じアドレス空間を使っているスレッドが他になければ、アドレス空間を解放する
+    fn release_vm(&mut self) {
+        if Rc::strong_count(&self.vm) == 1 {
+            // 既に解放済みでも、AddressSpace::free() は何もしないだけなので問題ない
+            self.vm.borrow_mut().free();
+        } else {
+            self.vm = Rc::new(RefCell::new(AddressSpace::empty()));
+        }
+    }
+
+    // 実行中のプロセス以外で、終了済みのプロセスを回収する
+    // - ProcExit: 待っている親がいないので、プロセス管理構造体ごと解放する
+    // - Zombie: 親が終了ステータスを受け取るまでプロセス管理構造体を残し、アドレス空間だけ解放する
+    fn reap() {
+        unsafe {
+            let processes = &*(&raw const PROCESS_TABLE.processes);
+            let mut exited = Vec::new();
+            for &proc in processes.iter() {
+                if proc == PROCESS_TABLE.current {
+                    continue;
+                }
+
+                match (*proc).state {
+                    ProcessState::ProcExit => exited.push(proc),
+                    ProcessState::Zombie => (*proc).release_vm(),
+                    _ => {}
+                }
+            }
+
+            // 回収するプロセスはプロセス一覧から外れるので、走査し終えてから解放する
+            for proc in exited {
+                Process::destroy(proc);
+            }
+
+            // どのプロセスからもマッピングされなくなった共有メモリを解放する
+            shm::collect();
+        }
+    }
+
+    // 実行中のプロセスを終了させ、他のプロセスに切り替える
+    // 親プロセスが Process::wait() で終了ステータスを受け取れるように、Zombie として残す
+    pub fn exit_current(status: i32) -> ! {
+        unsafe {
+            if PROCESS_TABLE.current.is_null() {
+                panic!("invalid process state");
+            }
+
+            let current = &mut *PROCESS_TABLE.current;
+            current.exit_status = status;
+            // 引き取られたプロセスは、引き取り先が自分の pid を知らず終了を待たないので、そのまま回収する
+            if !current.parent.is_null() && !current.orphaned {
+                current.set_state(ProcessState::Zombie);
+                (*current.parent).child_exit.wake_all();
+                Process::kill((*current.parent).pid, SIGCHLD);
+            } else {
+                current.set_state(ProcessState::ProcExit);
+            }
+
+            // 全てのファイルディスクリプタを閉じる (パイプの相手に EOF などを伝えるため)
+            current.fds.clear();
+
+            // このプロセスにメッセージを送って待っているクライアントを起こす
+            ipc_abort(&mut current.ipc);
+
+            // 子プロセスは最初のユーザープロセスに引き取らせる
+            // 最初のユーザープロセス自身が終了する場合は、引き取り先がないので親なしにする
+            // 引き取り先は引き取ったプロセスの終了を待たないので、終了済みのものは回収する
+            let init = if PROCESS_TABLE.init == PROCESS_TABLE.current {
+                core::ptr::null_mut()
+            } else {
+                PROCESS_TABLE.init
+            };
+            for child in core::mem::take(&mut current.children) {
+                (*child).parent = init;
+                (*child).orphaned = true;
+                if !init.is_null() {
+                    (*init).children.push(child);
+                }
+                if (*child).state == ProcessState::Zombie {
+                    (*child).state = ProcessState::ProcExit;
+                }
+            }
+        }
+
+        Process::yield_proc();
+        panic!("unreachable");
+    }
+
+    // 実行中のプロセスの子プロセス pid が終了するまで待ち、終了ステータスを返す
+    // pid が実行中のプロセスの子プロセスでない場合は None を返す
+    pub fn wait(pid: i32) -> Option<i32> {
+        loop {
+            unsafe {
+                let child = &mut *PROCESS_TABLE.lookup(pid)?;
+                if child.parent != PROCESS_TABLE.current {
+                    return None;
+                }
+
+                if child.state == ProcessState::Zombie {
+                    let status = child.exit_status;
+                    Process::destroy(child);
+                    return Some(status);
+                }
+            }
+
+            // シグナルが届いていれば、待つのをやめる
+            if signal::interrupted() {
+                return None;
+            }
+
+            // 子プロセスが終了するまで眠る
+            unsafe { (*PROCESS_TABLE.current).child_exit.sleep() };
+        }
+    }
+
+    // 実行中のプロセスを、ms ミリ秒が経つまで眠らせる
+    // シグナルが届いた場合は、途中で起きて戻る
+    pub fn sleep_current(ms: u64) {
+        let deadline = timer::now() + timer::ms_to_ticks(ms);
+        while timer::now() < deadline && !signal::interrupted() {
+            unsafe {
+                let current = &mut *PROCESS_TABLE.current;
+                timer::add(deadline, current.pid);
+                current.set_state(ProcessState::Sleeping);
+            }
+
+            Process::yield_proc();
+        }
+    }
+
+    // 眠っているプロセス pid を実行可能な状態に戻す
+    pub fn wake(pid: i32) {
+        unsafe {
+            if let Some(proc) = PROCESS_TABLE.lookup(pid) {
+                if (*proc).state == ProcessState::Sleeping {
+                    (*proc).set_state(ProcessState::Runnable);
+                }
+            }
+        }
+    }
+
+    // Blocked のプロセス pid を実行可能な状態に戻す
+    // 起こしたかどうかを返す (既に終了している場合などは false)
+    pub fn unblock(pid: i32) -> bool {
+        unsafe {
+            if let Some(proc) = PROCESS_TABLE.lookup(pid) {
+                if (*proc).state == ProcessState::Blocked {
+                    (*proc).set_state(ProcessState::Runnable);
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    // プロセス pid にシグナル sig を送る
+    // 眠っているプロセスは起こし、待っているシステムコールを中断してシグナルを処理させる
+    // pid が終了していないユーザープロセスでない場合や sig が不正な場合は false を返す
+    pub fn kill(pid: i32, sig: usize) -> bool {
+        if !Signals::is_valid(sig) {
+            return false;
+        }
+
+        unsafe {
+            let proc = match PROCESS_TABLE.lookup(pid) {
+                Some(proc) if (*proc).is_user_process() => &mut *proc,
+                _ => return false,
+            };
+
+            match proc.state {
+                ProcessState::Runnable => {
+                    proc.signals.raise(sig);
+                }
+                ProcessState::Sleeping | ProcessState::Blocked => {
+                    if proc.signals.raise(sig) {
+                        proc.set_state(ProcessState::Runnable);
+                    }
+                }
+                _ => return false,
+            }
+        }
+
+        true
+    }
+
+    // 実行中のプロセスとアドレス空間を共有している他のスレッドに SIGKILL を送る
+    // 送られたスレッドは、ユーザーモードに戻る前に自分で終了する
+    pub fn kill_other_threads() {
+        unsafe {
+            let current = PROCESS_TABLE.current;
+            let processes = &*(&raw const PROCESS_TABLE.processes);
+            for &proc in processes.iter() {
+                if proc != current && Rc::ptr_eq(&(*proc).vm, &(*current).vm) {
+                    Process::kill((*proc).pid, SIGKILL);
+                }
+            }
+        }
+    }
+
+    // 最初のユーザープロセス (シェル) が実行しているフォアグラウンドのジョブにシグナルを送る
+    // - 親をたどってシェルに行き着くプロセス (孫以降や、シェルが引き取ったプロセスも含む) に送る
+    // - そのようなプロセスが動いていなければ、シェル自身が組み込みコマンド (sleep など) を実行しているので、シェルに送る
+    //   (子孫が動いている間はシェルはその終了を待っているだけなので、シェルには送らない)
+    pub fn kill_job(sig: usize) {
+        unsafe {
+            let init = PROCESS_TABLE.init;
+            if init.is_null() {
+                return;
+            }
+
+            let mut sent = false;
+            let processes = &*(&raw const PROCESS_TABLE.processes);
+            for &proc in processes.iter() {
+                if (*proc).is_descendant_of(init) && Process::kill((*proc).pid, sig) {
+                    sent = true;
+                }
+            }
+
+            if !sent {
+                Process::kill((*init).pid, sig);
+            }
+        }
+    }
+
+    // 親をたどって ancestor に行き着くかを返す
+    fn is_descendant_of(&self, ancestor: *mut Process) -> bool {
+        let mut proc = self.parent;
+        while !proc.is_null() {
+            if proc == ancestor {
+                return true;
+            }
+            proc = unsafe { (*proc).parent };
+        }
+
+        false
+    }
+
+    // アイドルプロセスとカーネルスレッド以外に、終了していないプロセスがあるかを返す
+    pub fn has_user_processes() -> bool {
+        unsafe {
+            let processes = &*(&raw const PROCESS_TABLE.processes);
+            processes.iter().any(|&p| {
+                (*p).is_user_process()
+                    && (*p).state != ProcessState::Unused
+                    && (*p).state != ProcessState::ProcExit
+            })
+        }
+    }
+
+    // アイドルプロセスでもカーネルスレッドでもないかを返す
+    pub fn is_user_process(&self) -> bool {
+        !core::ptr::eq(self, unsafe { PROCESS_TABLE.idol }) && self.kthread.is_none()
+    }
+
+    // 親プロセスのIDを返す (親がいない場合は 0)
+    pub fn ppid(&self) -> i32 {
+        if self.parent.is_null() {
+            0
+        } else {
+            unsafe { (*self.parent).pid }
+        }
+    }
+
+    pub fn nice(&self) -> i32 {
+        self.nice
+    }
+
+    // nice 値を変更し、スケジューラのレベルをその nice 値の基準に戻す
+    pub fn set_nice(&mut self, nice: i32) {
+        self.nice = nice.clamp(NICE_MIN, NICE_MAX);
+        self.level = base_level(self.nice);
+        self.ticks = 0;
     }
 
     pub fn set_state(&mut self, state: ProcessState) {
@@ -143,71 +644,197 @@ impl Process {
         }
     }
 
+    // 実行中のプロセスから、他の実行可能なプロセスに CPU を譲る
     pub fn yield_proc() {
+        Process::reap();
+        timer::wake_expired();
+
         unsafe {
-            // 実行可能なプロセスを探す
-            let mut next = PROCESS_TABLE.idol;
+            // 現在のプロセスが初期化されていない場合
+            if PROCESS_TABLE.current.is_null() {
+                PROCESS_TABLE.current = PROCESS_TABLE.idol;
+                return;
+            }
 
-            // 現在のプロセスが初期化されているか確認
-            if !PROCESS_TABLE.current.is_null() {
-                let current = &*PROCESS_TABLE.current;
+            Process::switch_to(Process::pick_next(false));
+        }
+    }
 
-                // 現在のプロセスからの相対位置でプロセスを探す
-                for i in 0..PROCS_MAX {
-                    let idx = (current.pid as usize + i) % PROCS_MAX;
-                    let proc = &mut PROCESS_TABLE.processes[idx];
+    // タイマー割り込みごとに呼ばれ、実行中のプロセスのタイムスライスを消費する
+    // タイムスライスを使い切ったプロセスはレベルを1つ下げ (CPUを使い続けるプロセスほど優先度が下がる)、
+    // 同じかより高いレベルのプロセスがあれば切り替える
+    pub fn tick() {
+        unsafe {
+            // 一定間隔で全プロセスのレベルを元に戻し、優先度の低いプロセスが飢餓状態になるのを防ぐ
+            PROCESS_TABLE.ticks += 1;
+            if PROCESS_TABLE.ticks % SCHED_BOOST_INTERVAL == 0 {
+                Process::boost();
+            }
 
-                    if proc.state == ProcessState::Runnable && proc.pid > 0 {
-                        next = proc as *mut Process;
-                        break;
-                    }
+            let current = &mut *PROCESS_TABLE.current;
+            current.ticks += 1;
+            if current.ticks < time_slice(current.level) {
+                return;
+            }
+
+            current.ticks = 0;
+            current.level = (current.level + 1).min(SCHED_LEVELS - 1);
+        }
+
+        Process::reap();
+        unsafe { Process::switch_to(Process::pick_next(true)) };
+    }
+
+    // 全プロセスのスケジューラのレベルを、nice 値で決まる基準のレベルに戻す
+    fn boost() {
+        unsafe {
+            let processes = &*(&raw const PROCESS_TABLE.processes);
+            for &proc in processes.iter() {
+                if (*proc).state != ProcessState::Unused {
+                    (*proc).level = base_level((*proc).nice);
+                    (*proc).ticks = 0;
                 }
+            }
+        }
+    }
+
+    // 次に実行するプロセスを選ぶ
+    // 実行可能なプロセスのうちレベルが最も高いものを選び、同じレベルの中ではスロットの順に回す
+    // - keep_current が false (自分から CPU を譲る) の場合、他に実行可能なプロセスがあればそちらを選ぶ
+    // - keep_current が true (タイムスライス切れ) の場合、実行中のプロセスよりレベルが高いか同じプロセスがなければ実行を続ける
+    unsafe fn pick_next(keep_current: bool) -> *mut Process {
+        unsafe {
+            let current = PROCESS_TABLE.current;
+            let processes = &*(&raw const PROCESS_TABLE.processes);
 
-                // 現在実行中のプロセス以外に、実行可能なプロセスがない場合は戻る
-                if next == PROCESS_TABLE.current {
-                    return;
+            // 現在のプロセスのスロットの次から順にプロセスを探す
+            let start = processes
+                .iter()
+                .position(|&p| p == current)
+                .map_or(0, |i| i + 1);
+            let mut next: *mut Process = core::ptr::null_mut();
+            for i in 0..processes.len() {
+                let proc = processes[(start + i) % processes.len()];
+                if proc == current || (*proc).state != ProcessState::Runnable || (*proc).pid == 0 {
+                    continue;
                 }
 
-                let prev = PROCESS_TABLE.current;
-                PROCESS_TABLE.current = next;
-
-                if !prev.is_null() && !next.is_null() {
-                    let prev_ref = &mut *prev;
-                    let next_ref = &mut *next;
-
-                    core::arch::asm!(
-                        // ページテーブルの物理ページ番号を計算
-                        "sfence.vma",
-                        "csrw satp, {satp}",
-                        "sfence.vma",
-                        // スタックポインタは下位アドレスの方向に伸びる(スタック領域の末尾から使われていく)ため、
-                        // `next.stack[next.stack.len()-1]`バイト目のアドレスをカーネルスタックの初期値として設定します。
-                        "csrw sscratch, {sscratch}",
-                        satp = in(reg) (SATP_SV32 | (next_ref.page_table.addr as usize / PAGE_SIZE)) as usize,
-                        sscratch = in(reg) &next_ref.stack[next_ref.stack.len()-1] as *const u8 as usize,
-                        options(nomem, nostack)
-                    );
-
-                    Process::switch_context(
-                        &mut prev_ref.sp as *mut usize,
-                        &mut next_ref.sp as *mut usize,
-                    );
+                if next.is_null() || (*proc).level < (*next).level {
+                    next = proc;
                 }
+            }
+
+            let current_runnable = (*current).state == ProcessState::Runnable && (*current).pid > 0;
+            if current_runnable
+                && (next.is_null() || (keep_current && (*current).level < (*next).level))
+            {
+                return current;
+            }
+
+            // 実行可能なプロセスがなければアイドルプロセスに切り替える
+            if next.is_null() {
+                PROCESS_TABLE.idol
             } else {
-                // 現在のプロセスが初期化されていない場合
-                if !next.is_null() {
-                    PROCESS_TABLE.current = next;
-                }
+                next
+            }
+        }
+    }
+
+    // 実行中のプロセスから next にコンテキストを切り替える
+    fn switch_to(next: *mut Process) {
+        unsafe {
+            // 現在実行中のプロセスを続ける場合は戻る
+            if next == PROCESS_TABLE.current {
+                return;
+            }
+
+            let prev = PROCESS_TABLE.current;
+            PROCESS_TABLE.current = next;
+
+            if !prev.is_null() && !next.is_null() {
+                let prev_ref = &mut *prev;
+                let next_ref = &mut *next;
+
+                core::arch::asm!(
+                    // ページテーブルの物理ページ番号を計算
+                    "sfence.vma",
+                    "csrw satp, {satp}",
+                    "sfence.vma",
+                    // スタックポインタは下位アドレスの方向に伸びる(スタック領域の末尾から使われていく)ため、
+                    // カーネルスタックの末尾のアドレスをカーネルスタックの初期値として設定します。
+                    "csrw sscratch, {sscratch}",
+                    satp = in(reg) (SATP_SV32 | (next_ref.vm.borrow().page_table.addr as usize / PAGE_SIZE)) as usize,
+                    sscratch = in(reg) next_ref.stack.as_ptr().add(next_ref.stack.len()) as usize,
+                    options(nomem, nostack)
+                );
+
+                Process::switch_context(
+                    &mut prev_ref.sp as *mut usize,
+                    &mut next_ref.sp as *mut usize,
+                );
             }
         }
     }
 }
 
+// nice 値から、プロセスがいられる最も高いスケジューラのレベルを求める
+// NICE_MIN..=NICE_MAX を 0..SCHED_LEVELS に均等に割り当てる (nice 値 0 はレベル 4)
+fn base_level(nice: i32) -> usize {
+    (nice - NICE_MIN) as usize * SCHED_LEVELS / (NICE_MAX - NICE_MIN + 1) as usize
+}
+
+// レベルごとのタイムスライスの長さ (タイマー割り込みの回数)
+// 優先度の低いレベルほど長く実行できるが、実行される機会は少なくなる
+fn time_slice(level: usize) -> usize {
+    1 << (level / 2)
+}
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum ProcessState {
     Unused,
     Runnable,
-    ProcExit,
+    Sleeping, // タイマーの期限を待っている
+    Blocked,  // WaitQueue で起こされるのを待っている
+    Zombie,   // 終了したが、親プロセスが終了ステータスを受け取っていない
+    ProcExit, // 終了し、回収を待っている
+}
+
+// カーネルスレッドを作る
+// カーネルスレッドは S-Mode のまま自分のカーネルスタックで entry(arg) を実行し、戻ってきたら終了する
+// カーネル実行中はタイマー割り込みが発生しないので、定期的に眠るか CPU を譲る必要がある
+pub fn spawn_kernel_thread(entry: fn(usize), arg: usize) -> *mut Process {
+    // カーネル領域だけをマッピングしたアドレス空間を使う
+    let vm = match AddressSpace::new(Image::Empty) {
+        Some(vm) => vm,
+        None => panic!("failed to create kernel thread"),
+    };
+
+    let (pid, proc) = Process::alloc_slot();
+
+    unsafe {
+        let stack_top = proc.stack.as_mut_ptr().add(proc.stack.len());
+        let sp = Process::push_switch_frame(stack_top as *mut usize, kernel_thread_entry as usize);
+        proc.setup(
+            pid,
+            core::ptr::null_mut(),
+            Some((entry, arg)),
+            Rc::new(RefCell::new(vm)),
+            sp,
+        );
+    }
+
+    proc as *mut Process
+}
+
+// カーネルスレッドが最初にコンテキストスイッチで切り替わったときに、ここから実行を始める
+fn kernel_thread_entry() -> ! {
+    let (entry, arg) = match unsafe { (*PROCESS_TABLE.current).kthread } {
+        Some(kthread) => kthread,
+        None => panic!("not a kernel thread"),
+    };
+
+    entry(arg);
+    Process::exit_current(0);
 }
 
 fn user_entry() -> ! {
@@ -216,7 +843,7 @@ fn user_entry() -> ! {
             "csrw sepc, {sepc}",
             "csrw sstatus, {sstatus}",
             "sret",
-            sepc = in(reg) USER_BASE,
+            sepc = in(reg) (*PROCESS_TABLE.current).vm.borrow().entry,
             sstatus = in(reg) (SSTATUS_SPIE | SSTATUS_SUM),
             options(noreturn)
         );
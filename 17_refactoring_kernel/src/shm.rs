@@ -0,0 +1,102 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use crate::common::{PAGE_SIZE, SHM_PAGES_MAX};
+use crate::memory::{Paddr, Vaddr, align_up, alloc_pages, is_page_shared, release_page};
+use crate::process::{PROCESS_TABLE, ProcessState};
+
+/*
+共有メモリ
+- 複数のプロセスが、同じ物理ページを自分のアドレス空間の好きな位置にマッピングできる
+- 物理ページの参照カウントで寿命を管理する
+  - 共有メモリ自身が各ページへの参照を1つ持ち、マッピングするたびに参照を1つ増やす
+  - マッピングはアドレス空間 (ページテーブル) を破棄したときに参照を手放す
+  - 全てのマッピングがなくなった共有メモリは collect() で破棄し、ページを解放する
+*/
+struct SharedMemory {
+    frames: Vec<Paddr>, // 共有する物理ページ
+    owner: i32,         // 作成したプロセス
+    mapped: bool,       // 一度でもマッピングされたか
+}
+
+impl SharedMemory {
+    // いずれかのアドレス空間にマッピングされているかを返す
+    fn is_mapped(&self) -> bool {
+        self.frames.iter().any(|&paddr| is_page_shared(paddr))
+    }
+}
+
+static mut SHARED_MEMORIES: BTreeMap<i32, SharedMemory> = BTreeMap::new();
+static mut NEXT_SHM_ID: i32 = 1;
+
+// size バイトの共有メモリを作り、そのIDを返す
+// 大きさが不正な場合は -1 を返す
+pub fn shm_create(size: usize) -> i32 {
+    // 切り上げで桁あふれしないよう、先に上限と比べる
+    if size == 0 || size > SHM_PAGES_MAX * PAGE_SIZE {
+        return -1;
+    }
+
+    let pages = align_up(size, PAGE_SIZE) / PAGE_SIZE;
+
+    unsafe {
+        let id = NEXT_SHM_ID;
+        NEXT_SHM_ID += 1;
+
+        let shm = SharedMemory {
+            frames: (0..pages).map(|_| alloc_pages(1)).collect(),
+            owner: (*PROCESS_TABLE.current).pid,
+            mapped: false,
+        };
+        (*(&raw mut SHARED_MEMORIES)).insert(id, shm);
+
+        id
+    }
+}
+
+// 共有メモリ id を、実行中のプロセスの vaddr からの領域にマッピングする
+// 成功した場合は 0 を、id が存在しないか vaddr に領域を用意できない場合は -1 を返す
+pub fn shm_map(id: i32, vaddr: Vaddr) -> i32 {
+    unsafe {
+        let shm = match (*(&raw mut SHARED_MEMORIES)).get_mut(&id) {
+            Some(shm) => shm,
+            None => return -1,
+        };
+
+        let current = &mut *PROCESS_TABLE.current;
+        if !current.vm.borrow_mut().map_shared(vaddr, &shm.frames) {
+            return -1;
+        }
+
+        shm.mapped = true;
+        0
+    }
+}
+
+// どこにもマッピングされなくなった共有メモリを破棄する
+// 一度もマッピングされていない共有メモリは、作成したプロセスが終了するまで残す
+pub fn collect() {
+    unsafe {
+        let shared_memories = &mut *(&raw mut SHARED_MEMORIES);
+        shared_memories.retain(|_, shm| {
+            if shm.is_mapped() || (!shm.mapped && is_alive(shm.owner)) {
+                return true;
+            }
+
+            for &paddr in shm.frames.iter() {
+                release_page(paddr);
+            }
+            false
+        });
+    }
+}
+
+fn is_alive(pid: i32) -> bool {
+    match unsafe { PROCESS_TABLE.lookup(pid) } {
+        Some(proc) => matches!(
+            unsafe { (*proc).state },
+            ProcessState::Runnable | ProcessState::Sleeping | ProcessState::Blocked
+        ),
+        None => false,
+    }
+}
@@ -0,0 +1,209 @@
+use crate::TrapFrame;
+use crate::common::{NSIG, SIG_DFL, SIG_IGN, SIGCHLD, SIGKILL};
+use crate::process::{PROCESS_TABLE, Process};
+
+/*
+シグナル
+- kill で送られたシグナルは、受け取るプロセスの pending に記録する
+- ユーザーモードに戻る直前 (handle_trap の最後) に deliver() で1つずつ処理する
+  - SIG_DFL: 既定の動作 (SIGCHLD は無視、それ以外はプロセスを終了)
+  - SIG_IGN: 無視
+  - それ以外: ユーザーが登録したハンドラを呼び出す
+- ハンドラを呼び出すときは、元の TrapFrame をユーザースタックに積み、
+  ハンドラから戻ると restorer (sigreturn を呼ぶユーザーの関数) に戻るようにする
+- SIGKILL は捕捉も無視もできない
+*/
+
+// ハンドラを呼び出すときにユーザースタックに積むフレーム
+// sigreturn で、ハンドラを呼び出す前の状態に戻すために使う
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct SignalFrame {
+    frame: TrapFrame, // ハンドラを呼び出す前のレジスタ
+    blocked: u32,     // ハンドラを呼び出す前の blocked
+}
+
+// プロセスごとのシグナルの状態
+#[derive(Clone)]
+pub struct Signals {
+    pending: u32,            // 届いているが、まだ処理していないシグナル
+    blocked: u32,            // ハンドラの実行中で、届けるのを保留しているシグナル
+    handlers: [usize; NSIG], // シグナルごとの動作 (SIG_DFL, SIG_IGN またはハンドラのアドレス)
+    restorer: usize,         // ハンドラから戻る先 (sigreturn を呼ぶユーザーの関数)
+}
+
+impl Signals {
+    pub const fn new() -> Self {
+        Signals {
+            pending: 0,
+            blocked: 0,
+            handlers: [SIG_DFL; NSIG],
+            restorer: 0,
+        }
+    }
+
+    pub fn is_valid(sig: usize) -> bool {
+        0 < sig && sig < NSIG
+    }
+
+    // fork やスレッドの作成で引き継ぐ状態 (届いているシグナルは引き継がない)
+    pub fn inherit(&self) -> Self {
+        Signals {
+            pending: 0,
+            ..self.clone()
+        }
+    }
+
+    // exec で新しいプログラムに置き換えるときに、登録されたハンドラを既定の動作に戻す
+    // ハンドラのアドレスは新しいプログラムでは意味を持たないが、無視する設定は引き継ぐ
+    pub fn reset_handlers(&mut self) {
+        for handler in self.handlers.iter_mut() {
+            if *handler != SIG_IGN {
+                *handler = SIG_DFL;
+            }
+        }
+        self.restorer = 0;
+    }
+
+    // シグナル sig の動作を handler に変更し、変更前の動作を返す
+    // ハンドラは restorer に戻るように呼び出す
+    // sig が不正な場合や SIGKILL の場合は None を返す
+    pub fn set_handler(&mut self, sig: usize, handler: usize, restorer: usize) -> Option<usize> {
+        if !Signals::is_valid(sig) || sig == SIGKILL {
+            return None;
+        }
+
+        if handler != SIG_DFL && handler != SIG_IGN {
+            self.restorer = restorer;
+        }
+
+        Some(core::mem::replace(&mut self.handlers[sig], handler))
+    }
+
+    fn is_ignored(&self, sig: usize) -> bool {
+        match self.handlers[sig] {
+            SIG_IGN => sig != SIGKILL,
+            SIG_DFL => sig == SIGCHLD,
+            _ => false,
+        }
+    }
+
+    // シグナル sig を届ける
+    // すぐに処理すべきシグナルであれば true を返す (無視するシグナルや、保留するシグナルは false)
+    pub fn raise(&mut self, sig: usize) -> bool {
+        if self.is_ignored(sig) {
+            return false;
+        }
+
+        self.pending |= 1 << sig;
+        sig == SIGKILL || (self.blocked & (1 << sig)) == 0
+    }
+
+    // すぐに処理すべきシグナルが届いているかを返す
+    pub fn has_deliverable(&self) -> bool {
+        (self.pending & (!self.blocked | (1 << SIGKILL))) != 0
+    }
+
+    // 次に処理するシグナルを取り出す (SIGKILL を最優先し、それ以外は番号の小さい順)
+    fn take(&mut self) -> Option<usize> {
+        let deliverable = if (self.pending & (1 << SIGKILL)) != 0 {
+            1 << SIGKILL
+        } else {
+            self.pending & !self.blocked
+        };
+        if deliverable == 0 {
+            return None;
+        }
+
+        let sig = deliverable.trailing_zeros() as usize;
+        self.pending &= !(1 << sig);
+        Some(sig)
+    }
+}
+
+// 実行中のプロセスに、すぐに処理すべきシグナルが届いているかを返す
+// 眠って待つシステムコールは、これが true なら待つのをやめてユーザーモードに戻る
+pub fn interrupted() -> bool {
+    unsafe {
+        let current = PROCESS_TABLE.current;
+        !current.is_null() && (*current).signals.has_deliverable()
+    }
+}
+
+// ユーザーモードに戻る直前に、実行中のプロセスに届いているシグナルを処理する
+// ハンドラを呼び出す場合は、ハンドラから始まるように TrapFrame を書き換える
+pub fn deliver(f: &mut TrapFrame) {
+    let current = unsafe { &mut *PROCESS_TABLE.current };
+
+    while let Some(sig) = current.signals.take() {
+        let handler = if sig == SIGKILL {
+            SIG_DFL
+        } else {
+            current.signals.handlers[sig]
+        };
+
+        match handler {
+            SIG_IGN => continue,
+            SIG_DFL if sig == SIGCHLD => continue,
+            SIG_DFL => {
+                crate::common::println!("process {} killed by signal {}", current.pid, sig);
+                Process::exit_current(128 + sig as i32);
+            }
+            _ => {}
+        }
+
+        // ユーザースタックにフレームを積む (16バイト境界に揃える)
+        let frame_size = core::mem::size_of::<SignalFrame>();
+        let sp = (f.sp as usize).wrapping_sub(frame_size) & !0xf;
+        if !current.vm.borrow_mut().prepare(sp, frame_size, true) {
+            crate::common::println!(
+                "process {} killed: bad signal stack at {:x}",
+                current.pid,
+                sp
+            );
+            Process::exit_current(128 + sig as i32);
+        }
+
+        unsafe {
+            core::ptr::write_unaligned(
+                sp as *mut SignalFrame,
+                SignalFrame {
+                    frame: *f,
+                    blocked: current.signals.blocked,
+                },
+            );
+        }
+
+        // ハンドラの実行中は、同じシグナルを保留する
+        current.signals.blocked |= 1 << sig;
+
+        // handler(sig) を呼び出し、戻ると restorer から sigreturn を呼ぶようにする
+        f.sp = sp as i32;
+        f.a0 = sig as i32;
+        f.ra = current.signals.restorer as i32;
+        f.sepc = handler as u32;
+        return;
+    }
+}
+
+// ハンドラから戻ってきたときに、ユーザースタックに積んだフレームから元の状態に戻す
+// フレームを読み込めない場合は false を返す
+pub fn sigreturn(f: &mut TrapFrame) -> bool {
+    let current = unsafe { &mut *PROCESS_TABLE.current };
+
+    let sp = f.sp as usize;
+    let frame_size = core::mem::size_of::<SignalFrame>();
+    if !current.vm.borrow_mut().prepare(sp, frame_size, false) {
+        return false;
+    }
+
+    let saved = unsafe { core::ptr::read_unaligned(sp as *const SignalFrame) };
+
+    // sstatus はユーザーが書き換えられないよう、フレームの値ではなく現在の値を使う
+    *f = TrapFrame {
+        sstatus: f.sstatus,
+        ..saved.frame
+    };
+    current.signals.blocked = saved.blocked;
+    true
+}
@@ -0,0 +1,85 @@
+use alloc::vec::Vec;
+
+use crate::common::{SIE_STIE, TIMER_FREQ, TIMER_INTERVAL};
+use crate::process::Process;
+use crate::write_csr;
+
+// 期限を迎えたら、プロセス pid を起こすタイマー
+struct Timer {
+    deadline: u64, // 期限 (time CSR の値)
+    pid: i32,      // 起こすプロセス
+}
+
+// 期限が早い順に並べたタイマーの一覧
+static mut TIMERS: Vec<Timer> = Vec::new();
+
+// タイマー割り込みを有効にして、最初のタイムスライスを設定する
+pub fn init() {
+    write_csr!("sie", SIE_STIE);
+    set_next_tick();
+}
+
+// time CSR から現在の時刻を読み出す
+// RV32 では上位と下位の32ビットを別々に読むため、読んでいる間に桁上がりした場合は読み直す
+pub fn now() -> u64 {
+    loop {
+        let hi: u32;
+        let lo: u32;
+        let hi2: u32;
+        unsafe {
+            core::arch::asm!(
+                "rdtimeh {hi}",
+                "rdtime {lo}",
+                "rdtimeh {hi2}",
+                hi = out(reg) hi,
+                lo = out(reg) lo,
+                hi2 = out(reg) hi2,
+                options(nomem, nostack)
+            );
+        }
+
+        if hi == hi2 {
+            return ((hi as u64) << 32) | lo as u64;
+        }
+    }
+}
+
+// ミリ秒を time CSR の単位に変換する
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    ms.saturating_mul(TIMER_FREQ / 1000)
+}
+
+// deadline を過ぎたら、プロセス pid を起こすタイマーを登録する
+// 起こす時点でプロセスが終了していても、プロセスIDは再利用されないので他のプロセスを起こすことはない
+pub fn add(deadline: u64, pid: i32) {
+    unsafe {
+        let timers = &mut *(&raw mut TIMERS);
+        let index = timers.partition_point(|t| t.deadline <= deadline);
+        timers.insert(index, Timer { deadline, pid });
+    }
+}
+
+// タイマー割り込みの処理
+// 次のタイマー割り込みを設定し、期限を過ぎたタイマーのプロセスを起こす
+pub fn handle_interrupt() {
+    set_next_tick();
+    wake_expired();
+}
+
+// 期限を過ぎたタイマーのプロセスを起こす
+// タイマー割り込みはユーザーモードの実行中にしか発生しないので、プロセスの切り替え時にも呼ばれる
+pub fn wake_expired() {
+    let now = now();
+    unsafe {
+        let timers = &mut *(&raw mut TIMERS);
+        let expired = timers.partition_point(|t| t.deadline <= now);
+        for timer in timers.drain(..expired) {
+            Process::wake(timer.pid);
+        }
+    }
+}
+
+// 次のタイマー割り込みを設定する (保留中のタイマー割り込みもクリアされる)
+fn set_next_tick() {
+    crate::common::set_timer(now() + TIMER_INTERVAL);
+}
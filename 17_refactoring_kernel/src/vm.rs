@@ -0,0 +1,373 @@
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+
+use crate::common::{
+    PAGE_COW, PAGE_FLAGS_MASK, PAGE_R, PAGE_SHARED, PAGE_SIZE, PAGE_U, PAGE_V, PAGE_W, PAGE_X,
+    SATP_SV32,
+};
+use crate::elf::Elf;
+use crate::memory::{
+    Paddr, PageTable, Vaddr, align_down, align_up, alloc_pages, alloc_pages_uninit, is_aligned,
+    is_kernel_region, is_page_shared, release_page, share_page,
+};
+
+// ページの読み込み元になるイメージ
+#[derive(Clone)]
+pub enum Image {
+    Empty,
+    Static(&'static [u8]), // カーネルに埋め込まれたイメージ
+    Shared(Rc<[u8]>),      // ファイルの内容 (ファイルシステムや fork した子プロセスと共有する)
+}
+
+impl Image {
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            Image::Empty => &[],
+            Image::Static(image) => image,
+            Image::Shared(image) => image,
+        }
+    }
+}
+
+// ページフォルトの原因となったアクセスの種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    // アクセスに必要なページテーブルエントリの権限
+    fn flag(self) -> usize {
+        match self {
+            Access::Read => PAGE_R,
+            Access::Write => PAGE_W,
+            Access::Execute => PAGE_X,
+        }
+    }
+}
+
+// ユーザー空間の連続した仮想アドレス領域
+// [start, start + file_size) はイメージの file_offset から読み込み、残りはゼロで埋める
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    start: Vaddr,
+    end: Vaddr,
+    flags: usize, // ページテーブルエントリに設定する権限
+    file_offset: usize,
+    file_size: usize,
+}
+
+impl Region {
+    fn contains(&self, vaddr: Vaddr) -> bool {
+        align_down(self.start, PAGE_SIZE) <= vaddr && vaddr < align_up(self.end, PAGE_SIZE)
+    }
+
+    fn allows(&self, access: Access) -> bool {
+        (self.flags & access.flag()) != 0
+    }
+}
+
+// プロセスのアドレス空間
+// ユーザー領域のページは最初にアクセスされたときに割り当て、マッピングする (デマンドページング)
+pub struct AddressSpace {
+    pub page_table: PageTable,
+    pub entry: Vaddr, // ユーザープログラムのエントリポイント
+    regions: Vec<Region>,
+    image: Image,
+}
+
+impl AddressSpace {
+    // ページテーブルを持たない空のアドレス空間
+    pub const fn empty() -> Self {
+        AddressSpace {
+            page_table: PageTable { addr: 0 },
+            entry: 0,
+            regions: Vec::new(),
+            image: Image::Empty,
+        }
+    }
+
+    // ELF形式のイメージからアドレス空間を作る
+    // PT_LOAD セグメントごとに、p_flags の権限を持つ領域を p_vaddr に用意する
+    // イメージの形式が正しくない場合は None を返す
+    pub fn new(image: Image) -> Option<Self> {
+        let mut regions = Vec::new();
+        let mut entry = 0;
+
+        if !image.as_slice().is_empty() {
+            let elf = Elf::parse(image.as_slice())?;
+
+            for phdr in elf.program_headers().filter(|p| p.is_load()) {
+                let start = phdr.p_vaddr as Vaddr;
+                let end = start.checked_add(phdr.p_memsz as usize)?;
+
+                // ページ境界に切り上げるとアドレス空間の終わりを越えるセグメントは読み込めない
+                if end > align_down(usize::MAX, PAGE_SIZE) {
+                    return None;
+                }
+
+                // カーネル領域と重なるセグメントは読み込めない
+                if is_kernel_region(align_down(start, PAGE_SIZE), align_up(end, PAGE_SIZE)) {
+                    return None;
+                }
+
+                let mut flags = 0;
+                if phdr.is_readable() {
+                    flags |= PAGE_R;
+                }
+                if phdr.is_writable() {
+                    flags |= PAGE_W;
+                }
+                if phdr.is_executable() {
+                    flags |= PAGE_X;
+                }
+
+                // [p_vaddr, p_vaddr + p_filesz) はファイルから読み込み、
+                // 残りの [p_vaddr + p_filesz, p_vaddr + p_memsz) (.bss など) はゼロで埋める
+                if start < end {
+                    regions.push(Region {
+                        start,
+                        end,
+                        flags: flags | PAGE_U,
+                        file_offset: phdr.p_offset as usize,
+                        file_size: phdr.p_filesz as usize,
+                    });
+                }
+            }
+
+            // 同じアドレスを複数のセグメントに読み込むことはできない
+            // (別々のセグメントが同じページの別の部分を使うのは構わない)
+            regions.sort_by_key(|r: &Region| r.start);
+            if regions.windows(2).any(|w| w[0].end > w[1].start) {
+                return None;
+            }
+
+            // エントリポイントは実行可能な領域になければならない
+            entry = elf.entry();
+            if !regions
+                .iter()
+                .any(|r| r.contains(entry) && r.allows(Access::Execute))
+            {
+                return None;
+            }
+        }
+
+        Some(AddressSpace {
+            page_table: PageTable::new(),
+            entry,
+            regions,
+            image,
+        })
+    }
+
+    // ページに設定する権限を返す (どの領域にも含まれないページは 0)
+    // 複数のセグメントが同じページを使う場合は、それぞれの権限を合わせたものになる
+    fn page_flags(&self, page_vaddr: Vaddr) -> usize {
+        self.regions
+            .iter()
+            .filter(|r| r.contains(page_vaddr))
+            .fold(0, |flags, r| flags | r.flags)
+    }
+
+    // ページフォルトを処理する
+    // 正当なアクセスであればページを用意して true を返し、不正なアクセスであれば false を返す
+    pub fn handle_fault(&mut self, vaddr: Vaddr, access: Access) -> bool {
+        let page_vaddr = align_down(vaddr, PAGE_SIZE);
+        let flags = self.page_flags(page_vaddr);
+        if (flags & access.flag()) == 0 {
+            return false;
+        }
+
+        let pte = match self.page_table.lookup(page_vaddr) {
+            Some(pte) if (*pte & PAGE_V) != 0 => pte,
+            _ => {
+                self.load_page(page_vaddr, flags);
+                return true;
+            }
+        };
+
+        // 既にマッピング済みで、書き込み以外のアクセス
+        if access != Access::Write || (*pte & PAGE_W) != 0 {
+            return true;
+        }
+
+        // 書き込み可能な領域なのに書き込めないのはコピーオンライトのページだけ
+        if (*pte & PAGE_COW) == 0 {
+            return false;
+        }
+
+        let paddr = (*pte >> 10) * PAGE_SIZE;
+        let flags = (*pte & PAGE_FLAGS_MASK & !PAGE_COW) | PAGE_W;
+        if is_page_shared(paddr) {
+            // 他のプロセスと共有しているので、ページをコピーして自分専用にする
+            let page = alloc_pages_uninit(1);
+            unsafe {
+                core::ptr::copy_nonoverlapping(paddr as *const u8, page as *mut u8, PAGE_SIZE);
+            }
+            *pte = ((page / PAGE_SIZE) << 10) | flags;
+            release_page(paddr);
+        } else {
+            // 既に他の参照がなくなっていれば、そのまま書き込み可能にする
+            *pte = ((paddr / PAGE_SIZE) << 10) | flags;
+        }
+
+        unsafe {
+            core::arch::asm!("sfence.vma");
+        }
+        true
+    }
+
+    // カーネルがユーザー空間のバッファにアクセスする前に、範囲内のページを全て用意する
+    // vaddr が null か、範囲がアドレス空間の終わりを越える場合は false を返す
+    // (len が 0 でも null は拒否する。呼び出し側はそのまま from_raw_parts() でスライスを作るため)
+    pub fn prepare(&mut self, vaddr: Vaddr, len: usize, write: bool) -> bool {
+        if vaddr == 0 {
+            return false;
+        }
+
+        let access = if write { Access::Write } else { Access::Read };
+        let end = match vaddr.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+
+        let mut page_vaddr = align_down(vaddr, PAGE_SIZE);
+        while page_vaddr < end {
+            if !self.handle_fault(page_vaddr, access) {
+                return false;
+            }
+            page_vaddr = match page_vaddr.checked_add(PAGE_SIZE) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+
+        true
+    }
+
+    // 物理ページ frames を vaddr から順にマッピングし、読み書きできる領域として追加する (共有メモリ用)
+    // vaddr がページ境界でないか、カーネル領域や既存の領域と重なる場合は false を返す
+    pub fn map_shared(&mut self, vaddr: Vaddr, frames: &[Paddr]) -> bool {
+        let end = match frames
+            .len()
+            .checked_mul(PAGE_SIZE)
+            .and_then(|size| vaddr.checked_add(size))
+        {
+            Some(end) => end,
+            None => return false,
+        };
+
+        if !is_aligned(vaddr, PAGE_SIZE)
+            || is_kernel_region(vaddr, end)
+            || self
+                .regions
+                .iter()
+                .any(|r| align_down(r.start, PAGE_SIZE) < end && vaddr < align_up(r.end, PAGE_SIZE))
+        {
+            return false;
+        }
+
+        // 共有メモリのページは最初から全てマッピングしておく
+        // マッピングごとにページの参照を増やし、ページテーブルを破棄したときに手放す
+        let flags = PAGE_R | PAGE_W | PAGE_U | PAGE_SHARED;
+        for (i, &paddr) in frames.iter().enumerate() {
+            share_page(paddr);
+            self.page_table
+                .map_page(vaddr + i * PAGE_SIZE, paddr, flags);
+        }
+
+        self.regions.push(Region {
+            start: vaddr,
+            end,
+            flags,
+            file_offset: 0,
+            file_size: 0,
+        });
+
+        unsafe {
+            core::arch::asm!("sfence.vma");
+        }
+        true
+    }
+
+    // ページを割り当て、イメージの内容を読み込んで flags の権限でマッピングする
+    // ページを使う全てのセグメントについて、それぞれの範囲をイメージから読み込む
+    fn load_page(&mut self, page_vaddr: Vaddr, flags: usize) {
+        let page = alloc_pages(1);
+        let image = self.image.as_slice();
+
+        for region in self.regions.iter().filter(|r| r.contains(page_vaddr)) {
+            // ページのうち、イメージから読み込む範囲を求める
+            let file_start = region.start;
+            let file_end = region.start + region.file_size;
+            let copy_start = page_vaddr.max(file_start);
+            let copy_end = (page_vaddr + PAGE_SIZE).min(file_end);
+            if copy_start >= copy_end {
+                continue;
+            }
+
+            let offset = region.file_offset + (copy_start - region.start);
+            if offset + (copy_end - copy_start) > image.len() {
+                panic!("user image too short: offset={:#x}", offset);
+            }
+
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    image.as_ptr().add(offset),
+                    (page + (copy_start - page_vaddr)) as *mut u8,
+                    copy_end - copy_start,
+                );
+            }
+        }
+
+        self.page_table.map_page(page_vaddr, page, flags);
+
+        unsafe {
+            core::arch::asm!("sfence.vma");
+        }
+    }
+
+    // アドレス空間を複製する (fork用)
+    // ユーザーページはコピーせずに共有し、書き込まれたときにコピーする
+    pub fn fork(&mut self) -> AddressSpace {
+        let mut child = AddressSpace {
+            page_table: PageTable::new(),
+            entry: self.entry,
+            regions: self.regions.clone(),
+            image: self.image.clone(),
+        };
+
+        self.page_table.share_user_pages(&mut child.page_table);
+
+        // 自分のページテーブルのエントリを読み取り専用に変更したので、TLBを破棄する
+        unsafe {
+            core::arch::asm!("sfence.vma");
+        }
+
+        child
+    }
+
+    // アドレス空間を破棄する
+    pub fn free(&mut self) {
+        if self.page_table.addr != 0 {
+            self.page_table.free();
+        }
+        self.regions.clear();
+        self.image = Image::Empty;
+    }
+
+    // このアドレス空間のページテーブルに切り替える
+    pub fn activate(&self) {
+        unsafe {
+            core::arch::asm!(
+                "sfence.vma",
+                "csrw satp, {satp}",
+                "sfence.vma",
+                satp = in(reg) (SATP_SV32 | (self.page_table.addr / PAGE_SIZE)),
+                options(nomem, nostack)
+            );
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use alloc::collections::VecDeque;
+
+use crate::process::{PROCESS_TABLE, Process, ProcessState};
+
+// 条件が満たされるのを待つプロセスの待ち行列
+// 待つ側は条件を確認してから sleep() し、条件を満たした側が wake_one() / wake_all() で起こす
+// 起こされたプロセスは条件が満たされているとは限らないので、ループで確認し直す
+pub struct WaitQueue {
+    waiters: VecDeque<i32>, // 待っているプロセスのID
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: VecDeque::new(),
+        }
+    }
+
+    // 実行中のプロセスを Blocked にして、起こされるまで他のプロセスに CPU を譲る
+    // 起動処理中やアイドルプロセスのように眠らせるプロセスがない場合は、すぐに戻る (呼び出し側はビジーウェイトになる)
+    pub fn sleep(&mut self) {
+        unsafe {
+            let current = PROCESS_TABLE.current;
+            if current.is_null() || current == PROCESS_TABLE.idol {
+                return;
+            }
+
+            self.waiters.push_back((*current).pid);
+            (*current).set_state(ProcessState::Blocked);
+        }
+
+        Process::yield_proc();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+
+    // 待っているプロセスを1つ起こす
+    // 起こしたかどうかを返す (終了などで待つのをやめたプロセスは飛ばす)
+    pub fn wake_one(&mut self) -> bool {
+        while let Some(pid) = self.waiters.pop_front() {
+            if Process::unblock(pid) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    // 待っている全てのプロセスを起こす
+    pub fn wake_all(&mut self) {
+        while let Some(pid) = self.waiters.pop_front() {
+            Process::unblock(pid);
+        }
+    }
+}
@@ -7,18 +7,14 @@ rustup run nightly cargo build
 
 output_dir="target/riscv32i-unknown-none-elf/debug"
 output_elf="$output_dir/shell_elf"
-output_binary="$output_dir/shell_bin"
-output_object="$output_dir/shell_bin.o"
-
-llvm-objcopy \
-  --set-section-flags \
-  .bss=alloc,contents \
-  -O binary \
-  "$output_elf" \
-  "$output_binary"
+output_object="$output_dir/shell_elf.o"
 
+# カーネルは ELF を解析して読み込むので、ELF ファイルをそのまま埋め込む
 llvm-objcopy \
   -I binary \
   -O elf32-littleriscv \
-  "$output_binary" \
+  "$output_elf" \
   "$output_object"
+
+# カーネルがファイルシステムから exec できるように、ディスクイメージにも置く
+cp "$output_elf" ../17_refactoring_kernel/disk/shell.elf
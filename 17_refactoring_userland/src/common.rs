@@ -10,9 +10,8 @@ struct SyscallWriter;
 
 impl Write for SyscallWriter {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for c in s.chars() {
-            user_putchar(c);
-        }
+        // 標準出力に書き出す (パイプにつながっている場合もある)
+        user_write(1, s.as_bytes());
         Ok(())
     }
 }
@@ -33,6 +32,43 @@ pub const SYS_GETCHAR: usize = 2;
 pub const SYS_EXIT: usize = 3;
 pub const SYS_READFILE: usize = 4;
 pub const SYS_WRITEFILE: usize = 5;
+pub const SYS_FREE_PAGES: usize = 6;
+pub const SYS_SLABINFO: usize = 7;
+pub const SYS_FORK: usize = 8;
+pub const SYS_EXEC: usize = 9;
+pub const SYS_WAIT: usize = 10;
+pub const SYS_GETPID: usize = 11;
+pub const SYS_GETPPID: usize = 12;
+pub const SYS_NICE: usize = 13;
+pub const SYS_SETPRIORITY: usize = 14;
+pub const SYS_SLEEP: usize = 15;
+pub const SYS_THREAD_CREATE: usize = 16;
+pub const SYS_THREAD_EXIT: usize = 17;
+pub const SYS_THREAD_JOIN: usize = 18;
+pub const SYS_FUTEX_WAIT: usize = 19;
+pub const SYS_FUTEX_WAKE: usize = 20;
+pub const SYS_IPC_SEND: usize = 21;
+pub const SYS_IPC_RECV: usize = 22;
+pub const SYS_IPC_REPLY: usize = 23;
+pub const SYS_PIPE: usize = 24;
+pub const SYS_READ: usize = 25;
+pub const SYS_WRITE: usize = 26;
+pub const SYS_CLOSE: usize = 27;
+pub const SYS_DUP2: usize = 28;
+pub const SYS_SHM_CREATE: usize = 29;
+pub const SYS_SHM_MAP: usize = 30;
+pub const SYS_KILL: usize = 31;
+pub const SYS_SIGNAL: usize = 32;
+pub const SYS_SIGRETURN: usize = 33;
+
+// シグナル
+pub const SIGINT: usize = 2;
+pub const SIGUSR1: usize = 10;
+pub const SIGTERM: usize = 15;
+
+// プロセス間通信のメッセージ (固定長)
+pub const IPC_MSG_SIZE: usize = 32;
+pub type Message = [u8; IPC_MSG_SIZE];
 
 pub fn user_putchar(ch: char) {
     syscall(SYS_PUTCHAR, ch as usize, 0, 0, 0);
@@ -65,6 +101,188 @@ pub fn user_writefile(filename: &[u8], filename_len: usize, buf: &[u8], buf_len:
     );
 }
 
+// 空いている物理ページ数を返す
+pub fn user_free_pages() -> usize {
+    syscall(SYS_FREE_PAGES, 0, 0, 0, 0)
+}
+
+// カーネルのスラブキャッシュの統計情報をコンソールに表示する
+pub fn user_slabinfo() {
+    syscall(SYS_SLABINFO, 0, 0, 0, 0);
+}
+
+// プロセスを複製する
+// 親プロセスには子プロセスのpid、子プロセスには 0 が返る
+pub fn user_fork() -> usize {
+    syscall(SYS_FORK, 0, 0, 0, 0)
+}
+
+// ファイルシステム上のプログラムで、実行中のプロセスを置き換える
+// 成功した場合は戻ってこない
+pub fn user_exec(path: &[u8]) -> isize {
+    syscall(SYS_EXEC, path.as_ptr() as usize, path.len(), 0, 0) as isize
+}
+
+// 子プロセス pid が終了するまで待ち、終了ステータスを返す (子プロセスでない場合は -1)
+pub fn user_wait(pid: usize) -> i32 {
+    syscall(SYS_WAIT, pid, 0, 0, 0) as i32
+}
+
+pub fn user_getpid() -> i32 {
+    syscall(SYS_GETPID, 0, 0, 0, 0) as i32
+}
+
+// 親プロセスのIDを返す (親がいない場合は 0)
+pub fn user_getppid() -> i32 {
+    syscall(SYS_GETPPID, 0, 0, 0, 0) as i32
+}
+
+// nice 値を inc だけ増減し、変更後の nice 値を返す
+pub fn user_nice(inc: i32) -> i32 {
+    syscall(SYS_NICE, inc as usize, 0, 0, 0) as i32
+}
+
+// プロセス pid の nice 値を設定する (プロセスが存在しない場合は -1)
+pub fn user_setpriority(pid: usize, nice: i32) -> i32 {
+    syscall(SYS_SETPRIORITY, pid, nice as usize, 0, 0) as i32
+}
+
+// ms ミリ秒の間、実行を止める
+pub fn user_sleep(ms: u32) {
+    syscall(SYS_SLEEP, ms as usize, 0, 0, 0);
+}
+
+// 同じアドレス空間で entry(arg) を実行するスレッドを作り、スレッドのIDを返す
+// entry は戻らずに user_thread_exit() を呼ぶ必要がある
+pub fn user_thread_create(entry: extern "C" fn(usize) -> !, stack: &mut [u8], arg: usize) -> i32 {
+    // スタックは末尾から使われるので、末尾のアドレス (16バイト境界) を渡す
+    let stack_top = (stack.as_mut_ptr() as usize + stack.len()) & !0xf;
+    syscall(SYS_THREAD_CREATE, entry as usize, stack_top, arg, 0) as i32
+}
+
+// 実行中のスレッドだけを終了する
+pub fn user_thread_exit(status: i32) -> ! {
+    syscall(SYS_THREAD_EXIT, status as usize, 0, 0, 0);
+    loop {}
+}
+
+// スレッド tid が終了するまで待ち、終了ステータスを返す
+pub fn user_thread_join(tid: i32) -> i32 {
+    syscall(SYS_THREAD_JOIN, tid as usize, 0, 0, 0) as i32
+}
+
+// プロセス pid に msg を送り、返信を reply に受け取る
+// pid が存在しないか、返信前に終了した場合は -1 を返す
+pub fn user_ipc_send(pid: i32, msg: &Message, reply: &mut Message) -> i32 {
+    syscall(
+        SYS_IPC_SEND,
+        pid as usize,
+        msg.as_ptr() as usize,
+        reply.as_mut_ptr() as usize,
+        0,
+    ) as i32
+}
+
+// 自分宛てのメッセージが届くまで待って msg に受け取り、送信したプロセスのIDを返す
+pub fn user_ipc_recv(msg: &mut Message) -> i32 {
+    syscall(SYS_IPC_RECV, msg.as_mut_ptr() as usize, 0, 0, 0) as i32
+}
+
+// メッセージを受け取ったプロセス pid に返信する
+pub fn user_ipc_reply(pid: i32, reply: &Message) -> i32 {
+    syscall(SYS_IPC_REPLY, pid as usize, reply.as_ptr() as usize, 0, 0) as i32
+}
+
+// パイプを作り、読み込み側を fds[0]、書き込み側を fds[1] に受け取る
+pub fn user_pipe(fds: &mut [i32; 2]) -> i32 {
+    syscall(SYS_PIPE, fds.as_mut_ptr() as usize, 0, 0, 0) as i32
+}
+
+// fd から buf に読み込み、読み込んだバイト数を返す (0 は EOF、-1 はエラー)
+pub fn user_read(fd: i32, buf: &mut [u8]) -> i32 {
+    syscall(
+        SYS_READ,
+        fd as usize,
+        buf.as_mut_ptr() as usize,
+        buf.len(),
+        0,
+    ) as i32
+}
+
+// fd に data を全て書き込み、書き込んだバイト数を返す (-1 はエラー)
+pub fn user_write(fd: i32, data: &[u8]) -> i32 {
+    let mut written = 0;
+    while written < data.len() {
+        let rest = &data[written..];
+        let len = syscall(
+            SYS_WRITE,
+            fd as usize,
+            rest.as_ptr() as usize,
+            rest.len(),
+            0,
+        ) as i32;
+        if len <= 0 {
+            return if written == 0 { -1 } else { written as i32 };
+        }
+        written += len as usize;
+    }
+    written as i32
+}
+
+pub fn user_close(fd: i32) -> i32 {
+    syscall(SYS_CLOSE, fd as usize, 0, 0, 0) as i32
+}
+
+// old_fd と同じものを new_fd にも割り当てる
+pub fn user_dup2(old_fd: i32, new_fd: i32) -> i32 {
+    syscall(SYS_DUP2, old_fd as usize, new_fd as usize, 0, 0) as i32
+}
+
+// size バイトの共有メモリを作り、そのIDを返す (-1 はエラー)
+pub fn user_shm_create(size: usize) -> i32 {
+    syscall(SYS_SHM_CREATE, size, 0, 0, 0) as i32
+}
+
+// 共有メモリ id を vaddr (ページ境界) からマッピングする (-1 はエラー)
+pub fn user_shm_map(id: i32, vaddr: usize) -> i32 {
+    syscall(SYS_SHM_MAP, id as usize, vaddr, 0, 0) as i32
+}
+
+// プロセス pid にシグナル sig を送る (-1 はエラー)
+pub fn user_kill(pid: usize, sig: usize) -> i32 {
+    syscall(SYS_KILL, pid, sig, 0, 0) as i32
+}
+
+// シグナル sig が届いたときに handler(sig) を呼び出すようにする (-1 はエラー)
+// ハンドラから戻ると sigreturn_trampoline() に戻り、シグナルが届く前の状態から再開する
+pub fn user_signal(sig: usize, handler: extern "C" fn(usize)) -> i32 {
+    syscall(
+        SYS_SIGNAL,
+        sig,
+        handler as usize,
+        sigreturn_trampoline as usize,
+        0,
+    ) as i32
+}
+
+// シグナルハンドラの戻り先
+// カーネルはハンドラを呼び出す前の状態をスタックに積んでいるので、
+// sp を変えずに SYS_SIGRETURN を呼ぶ必要がある (そのため naked 関数にする)
+#[unsafe(naked)]
+extern "C" fn sigreturn_trampoline() -> ! {
+    core::arch::naked_asm!(
+        "li a4, {sysno}",
+        "ecall",
+        sysno = const SYS_SIGRETURN,
+    )
+}
+
+// 終了ステータスを親プロセスに渡して、プロセスを終了する
+pub fn user_exit(status: i32) -> ! {
+    syscall(SYS_EXIT, status as usize, 0, 0, 0);
+    loop {}
+}
+
 pub fn syscall(sysno: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize) -> usize {
     let mut a0 = arg0;
     let a1 = arg1;
@@ -85,3 +303,126 @@ pub fn syscall(sysno: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize)
 
     a0
 }
+
+// vaddr の値が expected と等しければ、futex_wake() で起こされるまで眠る
+// 眠った場合は 0、値が異なっていた場合は -1 を返す
+pub fn user_futex_wait(addr: *const u32, expected: u32) -> i32 {
+    syscall(SYS_FUTEX_WAIT, addr as usize, expected as usize, 0, 0) as i32
+}
+
+// addr の futex で待っているプロセスを最大 n 個起こし、起こした数を返す
+pub fn user_futex_wake(addr: *const u32, n: u32) -> i32 {
+    syscall(SYS_FUTEX_WAKE, addr as usize, n as usize, 0, 0) as i32
+}
+
+/*
+アトミック命令
+ターゲットの riscv32i にはA拡張 (アトミック命令) が含まれていないため、
+QEMU の CPU が対応している amoswap.w / amoadd.w を .insn で直接埋め込む
+
+  .insn r opcode, funct3, funct7, rd, rs1, rs2
+  - opcode = 0x2f (AMO), funct3 = 2 (32ビット)
+  - funct7 = funct5 << 2 | aq << 1 | rl (aq = rl = 1 で前後のメモリアクセスと順序付ける)
+*/
+
+// *ptr に val を書き込み、書き込む前の値を返す (amoswap.w.aqrl)
+pub fn atomic_swap(ptr: *mut u32, val: u32) -> u32 {
+    let old: u32;
+    unsafe {
+        core::arch::asm!(
+            ".insn r 0x2f, 2, 0x07, {old}, {ptr}, {val}",
+            old = out(reg) old,
+            ptr = in(reg) ptr,
+            val = in(reg) val,
+        );
+    }
+    old
+}
+
+// *ptr に val を加え、加える前の値を返す (amoadd.w.aqrl)
+pub fn atomic_add(ptr: *mut u32, val: u32) -> u32 {
+    let old: u32;
+    unsafe {
+        core::arch::asm!(
+            ".insn r 0x2f, 2, 0x03, {old}, {ptr}, {val}",
+            old = out(reg) old,
+            ptr = in(reg) ptr,
+            val = in(reg) val,
+        );
+    }
+    old
+}
+
+/*
+futex を使ったミューテックス
+- 0: ロックされていない
+- 1: ロックされていて、待っているスレッドはいない
+- 2: ロックされていて、待っているスレッドがいるかもしれない
+
+"Futexes Are Tricky" (Ulrich Drepper) の mutex を、swap だけで実装したもの
+*/
+pub struct Mutex {
+    state: core::cell::UnsafeCell<u32>,
+}
+
+unsafe impl Sync for Mutex {}
+
+impl Mutex {
+    pub const fn new() -> Self {
+        Mutex {
+            state: core::cell::UnsafeCell::new(0),
+        }
+    }
+
+    pub fn lock(&self) {
+        // 誰もロックしていなければ、そのまま取得できる
+        if atomic_swap(self.state.get(), 1) == 0 {
+            return;
+        }
+
+        // 待っているスレッドがいることを示す 2 にしてから眠る
+        while atomic_swap(self.state.get(), 2) != 0 {
+            user_futex_wait(self.state.get(), 2);
+        }
+    }
+
+    pub fn unlock(&self) {
+        // 待っているスレッドがいるかもしれなければ、1つ起こす
+        if atomic_swap(self.state.get(), 0) == 2 {
+            user_futex_wake(self.state.get(), 1);
+        }
+    }
+}
+
+/*
+futex を使った条件変数
+notify するたびに seq を増やし、wait() は seq が変わるまで眠る
+*/
+pub struct Condvar {
+    seq: core::cell::UnsafeCell<u32>,
+}
+
+unsafe impl Sync for Condvar {}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            seq: core::cell::UnsafeCell::new(0),
+        }
+    }
+
+    // mutex のロックを外して notify されるまで眠り、ロックを取り直して戻る
+    // notify されていなくても戻ることがあるので、呼び出し側は条件をループで確認する
+    pub fn wait(&self, mutex: &Mutex) {
+        let seq = unsafe { core::ptr::read_volatile(self.seq.get()) };
+        mutex.unlock();
+        user_futex_wait(self.seq.get(), seq);
+        mutex.lock();
+    }
+
+    // 待っている全てのスレッドを起こす
+    pub fn notify_all(&self) {
+        atomic_add(self.seq.get(), 1);
+        user_futex_wake(self.seq.get(), u32::MAX);
+    }
+}
@@ -27,12 +27,21 @@ pub unsafe extern "C" fn start() -> ! {
 
 #[unsafe(no_mangle)]
 fn main() -> ! {
+    // Ctrl-C でシェル自身が終了しないよう、SIGINT ではハンドラを呼ぶだけにする
+    // (実行中の組み込みコマンドは、眠っているシステムコールが中断されて終わる)
+    common::user_signal(common::SIGINT, on_interrupt);
+
     loop {
         'prompt: loop {
             common::print!("> ");
             let mut cmdline = [0u8; 128];
             for i in 0.. {
-                let c = common::user_getchar() as u8;
+                // 入力を待っている間に Ctrl-C が押されたら、入力中の行を捨てる
+                let c = common::user_getchar() as isize;
+                if c < 0 {
+                    continue 'prompt;
+                }
+                let c = c as u8;
                 common::user_putchar(c as char);
 
                 if i == cmdline.len() - 1 {
@@ -62,44 +71,315 @@ fn main() -> ! {
                 }
             };
 
-            match command {
-                "hello" => {
-                    common::println!("Hello world from shell!");
+            // "a | b" の形なら、a の標準出力を b の標準入力につないで実行する
+            match command.split_once('|') {
+                Some((left, right)) => run_pipeline(left.trim(), right.trim()),
+                None => run_command(command),
+            }
+
+            break;
+        }
+    }
+}
+
+// コマンドを1つ実行する
+fn run_command(command: &str) {
+    match command {
+        "upper" => {
+            // 標準入力を EOF まで読み込み、大文字にして標準出力に書き出す
+            let mut buf = [0u8; 128];
+            loop {
+                let len = common::user_read(0, &mut buf);
+                if len <= 0 {
+                    break;
                 }
-                "exit" => {
-                    // common::println!("exit from shell!");
-                    exit();
+                let data = &mut buf[..len as usize];
+                data.make_ascii_uppercase();
+                common::user_write(1, data);
+            }
+        }
+        "pipe" => {
+            run_pipeline("hello", "upper");
+        }
+        "free" => {
+            let pages = common::user_free_pages();
+            common::println!("free pages: {} ({} KiB)", pages, pages * 4096 / 1024);
+        }
+        "slabinfo" => {
+            common::user_slabinfo();
+        }
+        "hello" => {
+            common::println!("Hello world from shell!");
+        }
+        "exit" => {
+            // common::println!("exit from shell!");
+            exit();
+        }
+        "readfile" => {
+            // common::println!("read from shell!");
+            let filename = b"hello.txt";
+            let mut buf: [u8; 128] = [0; 128];
+            let buf_len = buf.len();
+            common::user_readfile(filename, filename.len(), &mut buf, buf_len);
+            let read =
+                core::str::from_utf8(&buf[..buf.iter().position(|&c| c == 0).unwrap()]).unwrap();
+            crate::common::println!("readfile: {:?}", read);
+        }
+        "writefile" => {
+            let filename = b"hello.txt";
+            let buf = b"Hello from shell!\n";
+            common::user_writefile(filename, filename.len(), buf, buf.len());
+        }
+        "fork" => {
+            let pid = common::user_fork();
+            if pid == 0 {
+                common::println!(
+                    "Hello world from child process! pid={}, ppid={}",
+                    common::user_getpid(),
+                    common::user_getppid()
+                );
+                common::user_exit(42);
+            }
+            common::println!("forked child process: pid={}", pid);
+            let status = common::user_wait(pid);
+            common::println!("child process {} exited with status {}", pid, status);
+        }
+        "ipc" => {
+            let pid = common::user_fork();
+            if pid == 0 {
+                // 子プロセスはサーバーとして、受け取ったメッセージを大文字にして返す
+                let mut msg: common::Message = [0; common::IPC_MSG_SIZE];
+                let client = common::user_ipc_recv(&mut msg);
+                msg.make_ascii_uppercase();
+                common::user_ipc_reply(client, &msg);
+                common::user_exit(0);
+            }
+
+            let mut msg: common::Message = [0; common::IPC_MSG_SIZE];
+            let text = b"hello from shell";
+            msg[..text.len()].copy_from_slice(text);
+            let mut reply: common::Message = [0; common::IPC_MSG_SIZE];
+            if common::user_ipc_send(pid as i32, &msg, &mut reply) == 0 {
+                let len = reply.iter().position(|&c| c == 0).unwrap_or(reply.len());
+                common::println!(
+                    "reply from {}: {}",
+                    pid,
+                    core::str::from_utf8(&reply[..len]).unwrap_or("?")
+                );
+            } else {
+                common::println!("ipc to {} failed", pid);
+            }
+            common::user_wait(pid);
+        }
+        "shm" => {
+            // 2つのプロセスが別々のアドレスに同じ共有メモリをマッピングし、
+            // 一方が書いた内容をもう一方が読む
+            // シェル自身のアドレス空間に共有メモリを残さないよう、子プロセスの中で行う
+            let reader = common::user_fork();
+            if reader == 0 {
+                let id = common::user_shm_create(4096);
+                if id < 0 || common::user_shm_map(id, SHM_READER_ADDR) < 0 {
+                    common::println!("shm failed");
+                    common::user_exit(1);
                 }
-                "readfile" => {
-                    // common::println!("read from shell!");
-                    let filename = b"hello.txt";
-                    let mut buf: [u8; 128] = [0; 128];
-                    let buf_len = buf.len();
-                    common::user_readfile(filename, filename.len(), &mut buf, buf_len);
-                    let read =
-                        core::str::from_utf8(&buf[..buf.iter().position(|&c| c == 0).unwrap()])
-                            .unwrap();
-                    crate::common::println!("readfile: {:?}", read);
+
+                let writer = common::user_fork();
+                if writer == 0 {
+                    if common::user_shm_map(id, SHM_WRITER_ADDR) < 0 {
+                        common::user_exit(1);
+                    }
+                    let text = b"hello from shared memory";
+                    let shared = unsafe { &mut *(SHM_WRITER_ADDR as *mut [u8; 4096]) };
+                    shared[..text.len()].copy_from_slice(text);
+                    common::user_exit(0);
                 }
-                "writefile" => {
-                    let filename = b"hello.txt";
-                    let buf = b"Hello from shell!\n";
-                    common::user_writefile(filename, filename.len(), buf, buf.len());
+
+                common::user_wait(writer);
+                let shared = unsafe { &*(SHM_READER_ADDR as *const [u8; 4096]) };
+                let len = shared.iter().position(|&c| c == 0).unwrap_or(shared.len());
+                common::println!(
+                    "shm {}: {}",
+                    id,
+                    core::str::from_utf8(&shared[..len]).unwrap_or("?")
+                );
+                common::user_exit(0);
+            }
+            common::user_wait(reader);
+        }
+        "signal" => {
+            // 自分自身にシグナルを送り、登録したハンドラが呼ばれてから元の処理に戻ることを確かめる
+            common::user_signal(common::SIGUSR1, on_signal);
+            common::user_kill(common::user_getpid() as usize, common::SIGUSR1);
+            common::println!("returned from signal handler");
+        }
+        "thread" => {
+            let tid = common::user_thread_create(
+                thread_main,
+                unsafe { &mut (*(&raw mut THREAD_STACKS))[0] },
+                7,
+            );
+            common::println!("created thread: tid={}", tid);
+            let status = common::user_thread_join(tid);
+            common::println!("thread {} exited with status {}", tid, status);
+        }
+        "mutex" => {
+            // 2つのスレッドで、ミューテックスで守ったカウンタを増やす
+            unsafe {
+                *(&raw mut COUNTER) = 0;
+                *(&raw mut FINISHED) = 0;
+            }
+            let stacks = unsafe { &mut *(&raw mut THREAD_STACKS) };
+            let [stack0, stack1] = stacks;
+            let tid0 = common::user_thread_create(counter_main, stack0, 0);
+            let tid1 = common::user_thread_create(counter_main, stack1, 1);
+
+            // 両方のスレッドが終わるまで、条件変数で待つ
+            MUTEX.lock();
+            while unsafe { *(&raw const FINISHED) } < 2 {
+                FINISHED_CV.wait(&MUTEX);
+            }
+            MUTEX.unlock();
+
+            common::user_thread_join(tid0);
+            common::user_thread_join(tid1);
+            common::println!(
+                "counter={} (expected {})",
+                unsafe { *(&raw const COUNTER) },
+                2 * COUNTER_LOOPS
+            );
+        }
+        _ if command.starts_with("kill ") => {
+            // kill <pid> [sig] (sig を省略した場合は SIGTERM)
+            let mut args = command["kill ".len()..].split_whitespace();
+            let pid = args.next().and_then(|s| s.parse::<usize>().ok());
+            let sig = match args.next() {
+                Some(s) => s.parse::<usize>().ok(),
+                None => Some(common::SIGTERM),
+            };
+            match (pid, sig) {
+                (Some(pid), Some(sig)) => {
+                    if common::user_kill(pid, sig) < 0 {
+                        common::println!("kill failed: pid={}, sig={}", pid, sig);
+                    }
                 }
-                _ => {
-                    common::println!("unknown command: {}", command);
+                _ => common::println!("usage: kill <pid> [sig]"),
+            }
+        }
+        _ if command.starts_with("nice ") => match command["nice ".len()..].trim().parse::<i32>() {
+            Ok(inc) => common::println!("nice={}", common::user_nice(inc)),
+            Err(_) => common::println!("usage: nice <increment>"),
+        },
+        _ if command.starts_with("renice ") => {
+            let mut args = command["renice ".len()..].split_whitespace();
+            match (
+                args.next().and_then(|s| s.parse::<usize>().ok()),
+                args.next().and_then(|s| s.parse::<i32>().ok()),
+            ) {
+                (Some(pid), Some(nice)) => {
+                    if common::user_setpriority(pid, nice) < 0 {
+                        common::println!("no such process: {}", pid);
+                    }
                 }
+                _ => common::println!("usage: renice <pid> <nice>"),
+            }
+        }
+        _ if command.starts_with("sleep ") => {
+            match command["sleep ".len()..].trim().parse::<u32>() {
+                Ok(ms) => common::user_sleep(ms),
+                Err(_) => common::println!("usage: sleep <ms>"),
             }
+        }
+        _ if command.starts_with("exec ") => {
+            let path = command["exec ".len()..].trim();
+            common::user_exec(path.as_bytes());
+            common::println!("exec failed: {}", path);
+        }
+        _ => {
+            common::println!("unknown command: {}", command);
+        }
+    }
+}
 
-            break;
+// left の標準出力をパイプで right の標準入力につなぎ、両方が終わるまで待つ
+fn run_pipeline(left: &str, right: &str) {
+    let mut fds = [0i32; 2];
+    if common::user_pipe(&mut fds) < 0 {
+        common::println!("pipe failed");
+        return;
+    }
+    let [read_fd, write_fd] = fds;
+
+    let writer = common::user_fork();
+    if writer == 0 {
+        common::user_dup2(write_fd, 1);
+        common::user_close(read_fd);
+        common::user_close(write_fd);
+        run_command(left);
+        common::user_exit(0);
+    }
+
+    let reader = common::user_fork();
+    if reader == 0 {
+        common::user_dup2(read_fd, 0);
+        common::user_close(read_fd);
+        common::user_close(write_fd);
+        run_command(right);
+        common::user_exit(0);
+    }
+
+    // シェルが書き込み側を持ったままだと、読み込み側に EOF が届かない
+    common::user_close(read_fd);
+    common::user_close(write_fd);
+    common::user_wait(writer);
+    common::user_wait(reader);
+}
+
+// "shm" コマンドで、共有メモリをマッピングするアドレス
+const SHM_READER_ADDR: usize = 0x2000_0000;
+const SHM_WRITER_ADDR: usize = 0x2010_0000;
+
+// "thread" / "mutex" コマンドで作るスレッドのスタック
+static mut THREAD_STACKS: [[u8; 4096]; 2] = [[0; 4096]; 2];
+
+// "mutex" コマンドで、スレッドが共有するデータ
+const COUNTER_LOOPS: u32 = 10000;
+static MUTEX: common::Mutex = common::Mutex::new();
+static FINISHED_CV: common::Condvar = common::Condvar::new();
+static mut COUNTER: u32 = 0;
+static mut FINISHED: u32 = 0;
+
+extern "C" fn on_interrupt(_sig: usize) {}
+
+extern "C" fn on_signal(sig: usize) {
+    common::println!("caught signal {}", sig);
+}
+
+extern "C" fn thread_main(arg: usize) -> ! {
+    common::println!("Hello world from thread! arg={}", arg);
+    common::user_thread_exit(arg as i32 * 6)
+}
+
+extern "C" fn counter_main(_arg: usize) -> ! {
+    for _ in 0..COUNTER_LOOPS {
+        MUTEX.lock();
+        unsafe {
+            let counter = core::ptr::read_volatile(&raw const COUNTER);
+            core::ptr::write_volatile(&raw mut COUNTER, counter + 1);
         }
+        MUTEX.unlock();
     }
+
+    MUTEX.lock();
+    unsafe { *(&raw mut FINISHED) += 1 };
+    FINISHED_CV.notify_all();
+    MUTEX.unlock();
+
+    common::user_thread_exit(0)
 }
 
 #[unsafe(no_mangle)]
 fn exit() -> ! {
-    common::syscall(common::SYS_EXIT, 0, 0, 0, 0);
-    loop {}
+    common::user_exit(0)
 }
 
 #[panic_handler]
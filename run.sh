@@ -8,6 +8,6 @@ pushd "./${d}_userland/" &&\
   ./build.sh &&\
   popd &&\
   pushd "./${d}_kernel/" &&\
-  (cd disk && tar cf ../disk.tar --format=ustar *.txt) &&\
+  (cd disk && tar cf ../disk.tar --format=ustar *) &&\
   cargo run &&\
   popd